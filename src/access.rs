@@ -0,0 +1,104 @@
+use crate::cidr::Cidr;
+use crate::utils;
+use crate::utils::WatchedFile;
+use once_cell::sync::Lazy;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+#[derive(Default)]
+struct Lists {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+static LISTS: Lazy<RwLock<Lists>> = Lazy::new(|| RwLock::new(Lists::default()));
+
+static BLOCKED: AtomicU64 = AtomicU64::new(0);
+
+/// Whether a client may use the proxy. Denied networks always win, and once an
+/// allow list is set, only clients inside it are let in.
+pub fn is_allowed(client: Option<IpAddr>) -> bool {
+    let lists = LISTS.read().unwrap();
+
+    let allowed = match client {
+        Some(ip) => {
+            !lists.deny.iter().any(|cidr| cidr.contains(ip))
+                && (lists.allow.is_empty() || lists.allow.iter().any(|cidr| cidr.contains(ip)))
+        }
+        None => lists.allow.is_empty(),
+    };
+
+    if !allowed {
+        BLOCKED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    allowed
+}
+
+/// Number of requests refused because of the allow and deny lists.
+pub fn blocked_count() -> u64 {
+    BLOCKED.load(Ordering::Relaxed)
+}
+
+fn reload(allow_file: &mut Option<WatchedFile>, deny_file: &mut Option<WatchedFile>) {
+    let allow = allow_file
+        .as_mut()
+        .and_then(WatchedFile::load_if_changed)
+        .map(|list| Cidr::parse_list(&list));
+    let deny = deny_file
+        .as_mut()
+        .and_then(WatchedFile::load_if_changed)
+        .map(|list| Cidr::parse_list(&list));
+
+    if allow.is_none() && deny.is_none() {
+        return;
+    }
+
+    let mut lists = LISTS.write().unwrap();
+    if let Some(allow) = allow {
+        lists.allow = allow;
+    }
+    if let Some(deny) = deny {
+        lists.deny = deny;
+    }
+
+    tracing::info!(
+        "Loaded IP lists: {} allowed and {} denied networks ({} requests blocked so far)",
+        lists.allow.len(),
+        lists.deny.len(),
+        blocked_count()
+    );
+}
+
+/// Loads `IP_ALLOW_FILE` and `IP_DENY_FILE`, then keeps checking them for
+/// changes every `IP_LISTS_RELOAD_INTERVAL` seconds.
+pub fn init() {
+    let mut allow_file = WatchedFile::from_env("IP_ALLOW_FILE");
+    let mut deny_file = WatchedFile::from_env("IP_DENY_FILE");
+
+    if allow_file.is_none() && deny_file.is_none() {
+        return;
+    }
+
+    // refuse to start open to everyone because of a missing list
+    for file in allow_file.iter().chain(deny_file.iter()) {
+        if let Err(e) = fs::metadata(file.path()) {
+            panic!("Could not read IP list {}: {}", file.path(), e);
+        }
+    }
+
+    reload(&mut allow_file, &mut deny_file);
+
+    let interval = Duration::from_secs(utils::get_env_number("IP_LISTS_RELOAD_INTERVAL", 30));
+
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(interval);
+        loop {
+            interval.tick().await;
+            reload(&mut allow_file, &mut deny_file);
+        }
+    });
+}
@@ -0,0 +1,291 @@
+use crate::metrics;
+use crate::utils;
+use crate::utils::WatchedFile;
+use actix_web::HttpRequest;
+use once_cell::sync::Lazy;
+use qstring::QString;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Name of the header and query parameter carrying the API key.
+pub const HEADER: &str = "x-api-key";
+pub const QUERY_PARAM: &str = "api_key";
+
+struct ApiKey {
+    label: String,
+    /// Unix timestamp after which the key is no longer accepted.
+    expires: Option<u64>,
+    /// Maximum number of requests per quota period.
+    max_requests: Option<u64>,
+    /// Maximum number of bytes sent per quota period.
+    max_bytes: Option<u64>,
+}
+
+impl ApiKey {
+    /// Parses a line of the keys file:
+    /// `<key> <label> [expires=<unix time>] [requests=<n>] [bytes=<n>]`
+    fn parse(line: &str) -> Option<(String, ApiKey)> {
+        let mut parts = line.split_whitespace();
+        let key = parts.next()?.to_string();
+        let label = parts.next()?.to_string();
+
+        let mut api_key = ApiKey {
+            label,
+            expires: None,
+            max_requests: None,
+            max_bytes: None,
+        };
+
+        for option in parts {
+            let (name, value) = option.split_once('=')?;
+            let value = value.parse::<u64>().ok()?;
+            match name {
+                "expires" => api_key.expires = Some(value),
+                "requests" => api_key.max_requests = Some(value),
+                "bytes" => api_key.max_bytes = Some(value),
+                _ => return None,
+            }
+        }
+
+        Some((key, api_key))
+    }
+}
+
+struct Window {
+    start: Instant,
+    requests: u64,
+    bytes: u64,
+}
+
+/// Usage of a key, kept across reloads of the keys file.
+pub struct Usage {
+    label: String,
+    requests: AtomicU64,
+    bytes: AtomicU64,
+    window: Mutex<Window>,
+}
+
+impl Usage {
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        metrics::record_api_key_bytes(&self.label, bytes);
+        self.window.lock().unwrap().bytes += bytes;
+    }
+}
+
+struct Config {
+    required: bool,
+    skip_qhash: bool,
+    quota_period: Duration,
+    reload_interval: Duration,
+}
+
+static CONFIG: Lazy<Config> = Lazy::new(|| Config {
+    // keys are required unless explicitly disabled
+    required: env::var("API_KEY_REQUIRED").is_err() || utils::get_env_bool("API_KEY_REQUIRED"),
+    skip_qhash: utils::get_env_bool("API_KEY_SKIP_QHASH"),
+    quota_period: Duration::from_secs(utils::get_env_number("API_KEY_QUOTA_PERIOD", 24 * 60 * 60)),
+    reload_interval: Duration::from_secs(utils::get_env_number("API_KEYS_RELOAD_INTERVAL", 30)),
+});
+
+static KEYS: Lazy<RwLock<Option<HashMap<String, ApiKey>>>> = Lazy::new(|| RwLock::new(None));
+
+static USAGE: Lazy<Mutex<HashMap<String, Arc<Usage>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub enum Auth {
+    /// API keys aren't configured.
+    Disabled,
+    /// No key was presented, but keys are optional.
+    Anonymous,
+    Valid {
+        /// The key when it came as the query parameter, carried over into
+        /// rewritten manifest URLs. Keys sent in the header are kept out of URLs.
+        query_key: Option<String>,
+        usage: Arc<Usage>,
+        skip_qhash: bool,
+    },
+}
+
+pub enum Rejection {
+    Missing,
+    Invalid,
+    Expired,
+    QuotaExceeded,
+}
+
+impl Rejection {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Rejection::Missing => "No API key provided",
+            Rejection::Invalid => "Invalid API key provided",
+            Rejection::Expired => "API key expired",
+            Rejection::QuotaExceeded => "API key quota exceeded",
+        }
+    }
+}
+
+/// Checks the API key presented in the `X-Api-Key` header or `api_key` query parameter.
+pub fn authenticate(req: &HttpRequest) -> Result<Auth, Rejection> {
+    let keys = KEYS.read().unwrap();
+    let Some(keys) = keys.as_ref() else {
+        return Ok(Auth::Disabled);
+    };
+
+    let header_key = req
+        .headers()
+        .get(HEADER)
+        .and_then(|key| key.to_str().ok())
+        .map(|key| key.to_string());
+    let query_key = QString::from(req.query_string())
+        .get(QUERY_PARAM)
+        .map(|key| key.to_string());
+
+    let Some(presented) = header_key.or_else(|| query_key.clone()) else {
+        return if CONFIG.required {
+            Err(Rejection::Missing)
+        } else {
+            Ok(Auth::Anonymous)
+        };
+    };
+
+    let Some(key) = keys.get(&presented) else {
+        return Err(Rejection::Invalid);
+    };
+
+    if key.expires.is_some_and(|expires| utils::now() > expires) {
+        return Err(Rejection::Expired);
+    }
+
+    let usage = USAGE
+        .lock()
+        .unwrap()
+        .entry(presented.clone())
+        .or_insert_with(|| {
+            Arc::new(Usage {
+                label: key.label.clone(),
+                requests: AtomicU64::new(0),
+                bytes: AtomicU64::new(0),
+                window: Mutex::new(Window {
+                    start: Instant::now(),
+                    requests: 0,
+                    bytes: 0,
+                }),
+            })
+        })
+        .clone();
+
+    {
+        let mut window = usage.window.lock().unwrap();
+
+        if window.start.elapsed() >= CONFIG.quota_period {
+            *window = Window {
+                start: Instant::now(),
+                requests: 0,
+                bytes: 0,
+            };
+        }
+
+        if key.max_requests.is_some_and(|max| window.requests >= max)
+            || key.max_bytes.is_some_and(|max| window.bytes >= max)
+        {
+            return Err(Rejection::QuotaExceeded);
+        }
+
+        window.requests += 1;
+    }
+
+    usage.requests.fetch_add(1, Ordering::Relaxed);
+    metrics::record_api_key_request(&usage.label);
+
+    // only a key that is already in the URL may end up in the URLs derived from it
+    let query_key = query_key.filter(|key| *key == presented);
+
+    Ok(Auth::Valid {
+        query_key,
+        usage,
+        skip_qhash: CONFIG.skip_qhash,
+    })
+}
+
+fn reload(file: &mut WatchedFile) {
+    let Some(content) = file.load_if_changed() else {
+        return;
+    };
+
+    let keys = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let parsed = ApiKey::parse(line);
+            if parsed.is_none() {
+                tracing::warn!("Ignoring invalid API key line in {}", file.path());
+            }
+            parsed
+        })
+        .collect::<HashMap<_, _>>();
+
+    tracing::info!("Loaded {} API keys", keys.len());
+
+    *KEYS.write().unwrap() = Some(keys);
+}
+
+/// Writes the usage of every key as `label<TAB>requests<TAB>bytes` lines.
+fn write_usage(path: &str) {
+    let keys = KEYS.read().unwrap();
+    let Some(keys) = keys.as_ref() else {
+        return;
+    };
+
+    let usage = USAGE.lock().unwrap();
+
+    let mut content = String::from("# label\trequests\tbytes\n");
+    for (key, api_key) in keys {
+        if let Some(usage) = usage.get(key) {
+            content.push_str(&format!(
+                "{}\t{}\t{}\n",
+                api_key.label,
+                usage.requests.load(Ordering::Relaxed),
+                usage.bytes.load(Ordering::Relaxed)
+            ));
+        }
+    }
+
+    // write to a temporary file first, so readers never see a partial file
+    let tmp_path = format!("{}.tmp", path);
+    if let Err(e) = fs::write(&tmp_path, content).and_then(|_| fs::rename(&tmp_path, path)) {
+        tracing::error!("Could not write API key usage to {}: {}", path, e);
+    }
+}
+
+/// Loads `API_KEYS_FILE` and keeps checking it for changes, so revoked keys are
+/// dropped without a restart. The usage of each key is written to
+/// `API_KEY_USAGE_FILE` at the same interval, if set.
+pub fn init() {
+    let Some(mut file) = WatchedFile::from_env("API_KEYS_FILE") else {
+        return;
+    };
+
+    if let Err(e) = fs::metadata(file.path()) {
+        panic!("Could not read API keys file {}: {}", file.path(), e);
+    }
+
+    reload(&mut file);
+
+    let usage_file = env::var("API_KEY_USAGE_FILE").ok();
+
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(CONFIG.reload_interval);
+        loop {
+            interval.tick().await;
+            reload(&mut file);
+            if let Some(usage_file) = usage_file.as_deref() {
+                write_usage(usage_file);
+            }
+        }
+    });
+}
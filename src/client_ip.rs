@@ -0,0 +1,248 @@
+use crate::cidr::Cidr;
+use actix_web::dev::Payload;
+use actix_web::http::header::HeaderMap;
+use actix_web::{FromRequest, HttpRequest};
+use once_cell::sync::Lazy;
+use std::convert::Infallible;
+use std::env;
+use std::future::{ready, Ready};
+use std::net::{IpAddr, SocketAddr};
+
+/// Peers allowed to tell us the client address through forwarding headers.
+/// Peers connecting over a Unix socket are always trusted, as they are local.
+static TRUSTED_PROXIES: Lazy<Vec<Cidr>> = Lazy::new(|| {
+    env::var("TRUSTED_PROXIES")
+        .map(|list| Cidr::parse_list(&list))
+        .unwrap_or_default()
+});
+
+/// A header such as `CF-Connecting-IP` to take the client address from instead
+/// of the standard forwarding headers. Like those, it is only honoured when sent
+/// by a trusted proxy.
+static CLIENT_IP_HEADER: Lazy<Option<String>> =
+    Lazy::new(|| env::var("CLIENT_IP_HEADER").ok().map(|h| h.to_lowercase()));
+
+/// The resolved IP address of the client, `None` when it can't be determined,
+/// e.g. for a Unix socket peer that didn't send any forwarding header.
+#[derive(Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl FromRequest for ClientIp {
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(ClientIp(resolve(req))))
+    }
+}
+
+fn is_trusted(trusted: &[Cidr], ip: Option<IpAddr>) -> bool {
+    match ip {
+        Some(ip) => trusted.iter().any(|cidr| cidr.contains(ip)),
+        None => true,
+    }
+}
+
+/// Parses an address as found in forwarding headers, which may be quoted,
+/// bracketed or carry a port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip().to_canonical());
+    }
+
+    node.strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .and_then(|node| node.parse::<IpAddr>().ok())
+}
+
+/// The chain of addresses from the `Forwarded` header, oldest first.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("forwarded")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, value)| parse_node(value))
+        })
+        .collect()
+}
+
+/// The chain of addresses from an `X-Forwarded-For` style header, oldest first.
+fn list_chain(headers: &HeaderMap, name: &str) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+/// Works out the client address of a request. Forwarding headers are only
+/// honoured when the peer is a trusted proxy, and the chain is walked back from
+/// the nearest hop, skipping other trusted proxies. `client_ip_header` takes the
+/// place of the standard headers when given.
+fn resolve_from(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted: &[Cidr],
+    client_ip_header: Option<&str>,
+) -> Option<IpAddr> {
+    let peer = peer.map(|ip| ip.to_canonical());
+
+    if !is_trusted(trusted, peer) {
+        return peer;
+    }
+
+    if let Some(header) = client_ip_header {
+        return walk_chain(trusted, peer, list_chain(headers, header));
+    }
+
+    let mut chain = forwarded_chain(headers);
+    if chain.is_empty() {
+        chain = list_chain(headers, "x-forwarded-for");
+    }
+    if chain.is_empty() {
+        chain = headers
+            .get("x-real-ip")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| vec![parse_node(value)])
+            .unwrap_or_default();
+    }
+
+    walk_chain(trusted, peer, chain)
+}
+
+/// Walks a chain of addresses back from the nearest hop, up to the first one
+/// that isn't a trusted proxy.
+fn walk_chain(
+    trusted: &[Cidr],
+    peer: Option<IpAddr>,
+    chain: Vec<Option<IpAddr>>,
+) -> Option<IpAddr> {
+    let mut client = peer;
+    for hop in chain.into_iter().rev() {
+        // obfuscated or unparsable hops end the trusted part of the chain
+        let Some(hop) = hop else {
+            break;
+        };
+        client = Some(hop);
+        if !is_trusted(trusted, Some(hop)) {
+            break;
+        }
+    }
+
+    client
+}
+
+pub fn resolve(req: &HttpRequest) -> Option<IpAddr> {
+    resolve_from(
+        req.peer_addr().map(|addr| addr.ip()),
+        req.headers(),
+        &TRUSTED_PROXIES,
+        CLIENT_IP_HEADER.as_deref(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn resolve(peer: &str, headers: &[(&'static str, &'static str)]) -> Option<IpAddr> {
+        resolve_with(peer, headers, None)
+    }
+
+    fn resolve_with(
+        peer: &str,
+        headers: &[(&'static str, &'static str)],
+        client_ip_header: Option<&str>,
+    ) -> Option<IpAddr> {
+        let trusted = Cidr::parse_list("10.0.0.0/8, 2001:db8::/32");
+
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        let peer = (!peer.is_empty()).then(|| peer.parse().unwrap());
+
+        resolve_from(peer, &map, &trusted, client_ip_header)
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn untrusted_peers_are_taken_as_is() {
+        let headers = [("x-forwarded-for", "203.0.113.7")];
+        assert_eq!(resolve("198.51.100.1", &headers), ip("198.51.100.1"));
+        assert_eq!(resolve("::ffff:198.51.100.1", &[]), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn chains_are_walked_back_to_the_first_untrusted_hop() {
+        let headers = [("x-forwarded-for", "192.0.2.1, 203.0.113.7, 10.0.0.2")];
+        assert_eq!(resolve("10.0.0.1", &headers), ip("203.0.113.7"));
+
+        // Forwarded takes precedence, across several header lines
+        let headers = [
+            ("x-forwarded-for", "192.0.2.1"),
+            ("forwarded", "for=\"[2001:db8:cafe::17]:4711\""),
+            ("forwarded", "for=203.0.113.7;proto=https, for=10.1.2.3"),
+        ];
+        assert_eq!(resolve("10.0.0.1", &headers), ip("203.0.113.7"));
+
+        let headers = [("x-real-ip", "203.0.113.7")];
+        assert_eq!(resolve("10.0.0.1", &headers), ip("203.0.113.7"));
+
+        // a chain of trusted proxies only
+        let headers = [("x-forwarded-for", "10.0.0.3")];
+        assert_eq!(resolve("10.0.0.1", &headers), ip("10.0.0.3"));
+    }
+
+    #[test]
+    fn unusable_hops_end_the_chain() {
+        let headers = [("forwarded", "for=203.0.113.7, for=_hidden")];
+        assert_eq!(resolve("10.0.0.1", &headers), ip("10.0.0.1"));
+
+        let headers = [("x-forwarded-for", "203.0.113.7, garbage")];
+        assert_eq!(resolve("10.0.0.1", &headers), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn unix_socket_peers_are_trusted() {
+        let headers = [("x-forwarded-for", "203.0.113.7")];
+        assert_eq!(resolve("", &headers), ip("203.0.113.7"));
+        assert_eq!(resolve("", &[]), None);
+    }
+
+    #[test]
+    fn client_ip_header_replaces_the_standard_ones() {
+        let headers = [
+            ("cf-connecting-ip", "203.0.113.7"),
+            ("x-forwarded-for", "192.0.2.1"),
+        ];
+        let header = Some("cf-connecting-ip");
+        assert_eq!(
+            resolve_with("10.0.0.1", &headers, header),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            resolve_with("198.51.100.1", &headers, header),
+            ip("198.51.100.1")
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use crate::api_keys;
+use crate::metrics::Route;
+use crate::utils;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::{Bytes, BytesMut};
+use futures_util::future::{BoxFuture, Shared};
+use futures_util::FutureExt;
+use once_cell::sync::Lazy;
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Method, Request, Url};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+const AUTH_HEADER: &str = "x-piped-cluster-auth";
+const URL_HEADER: &str = "x-piped-cluster-url";
+const STATUS_HEADER: &str = "x-piped-cluster-status";
+
+/// Prefix of the headers used between cluster nodes, these are never passed on to clients.
+pub const HEADER_PREFIX: &str = "x-piped-cluster-";
+
+struct Config {
+    nodes: Vec<String>,
+    this_node: String,
+    secret: String,
+    max_range: u64,
+    health_interval: Duration,
+}
+
+static CONFIG: Lazy<Option<Config>> = Lazy::new(|| {
+    let nodes = env::var("CLUSTER_NODES")
+        .ok()?
+        .split(',')
+        .map(|node| node.trim().trim_end_matches('/').to_string())
+        .filter(|node| !node.is_empty())
+        .collect::<Vec<_>>();
+
+    let this_node = env::var("CLUSTER_SELF")
+        .expect("CLUSTER_SELF must be set when CLUSTER_NODES is set")
+        .trim_end_matches('/')
+        .to_string();
+    let secret =
+        env::var("CLUSTER_SECRET").expect("CLUSTER_SECRET must be set when CLUSTER_NODES is set");
+
+    Some(Config {
+        nodes,
+        this_node,
+        secret,
+        max_range: utils::get_env_number("CLUSTER_MAX_RANGE", 1024 * 1024),
+        health_interval: Duration::from_secs(utils::get_env_number("CLUSTER_HEALTH_INTERVAL", 5)),
+    })
+});
+
+static HEALTHY: Lazy<RwLock<HashSet<String>>> = Lazy::new(|| {
+    RwLock::new(
+        CONFIG
+            .as_ref()
+            .map(|config| config.nodes.iter().cloned().collect())
+            .unwrap_or_default(),
+    )
+});
+
+static CLUSTER_CLIENT: Lazy<Client> = Lazy::new(|| {
+    Client::builder()
+        .connect_timeout(Duration::from_secs(2))
+        .build()
+        .unwrap()
+});
+
+pub fn is_enabled() -> bool {
+    CONFIG.is_some()
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    if is_enabled() {
+        cfg.route("/_cluster/fetch", web::to(fetch))
+            .route("/_cluster/health", web::get().to(health));
+    }
+}
+
+/// Periodically probes every other node and keeps the set of healthy nodes up to date.
+pub fn spawn_health_checks() {
+    let Some(config) = CONFIG.as_ref() else {
+        return;
+    };
+
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(config.health_interval);
+        loop {
+            interval.tick().await;
+
+            for node in &config.nodes {
+                if *node == config.this_node {
+                    continue;
+                }
+
+                let healthy = CLUSTER_CLIENT
+                    .get(format!("{}/_cluster/health", node))
+                    .header(AUTH_HEADER, &config.secret)
+                    .timeout(config.health_interval)
+                    .send()
+                    .await
+                    .is_ok_and(|resp| resp.status().is_success());
+
+                let mut nodes = HEALTHY.write().unwrap();
+                if healthy {
+                    if nodes.insert(node.clone()) {
+                        tracing::info!("Cluster node {} is healthy again", node);
+                    }
+                } else if nodes.remove(node) {
+                    tracing::warn!("Cluster node {} failed its health check", node);
+                }
+            }
+        }
+    });
+}
+
+// FNV-1a followed by a splitmix64 finalizer, stable across builds and nodes
+fn stable_hash(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in *part {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash ^= 0xff;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+fn normalize_url(url: &Url) -> String {
+    let mut pairs = url
+        .query_pairs()
+        .filter(|(key, _)| {
+            !matches!(
+                key.as_ref(),
+                "qhash" | "qh2" | "rewrite" | api_keys::QUERY_PARAM
+            )
+        })
+        .collect::<Vec<_>>();
+    pairs.sort();
+
+    let query = pairs
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    format!(
+        "{}{}?{}",
+        url.host_str().unwrap_or_default(),
+        url.path(),
+        query
+    )
+}
+
+/// Picks the node owning the given upstream URL using rendezvous hashing over the healthy nodes.
+fn owner(config: &Config, url: &Url) -> Option<String> {
+    let key = normalize_url(url);
+    let healthy = HEALTHY.read().unwrap();
+
+    config
+        .nodes
+        .iter()
+        .filter(|node| **node == config.this_node || healthy.contains(*node))
+        .max_by_key(|node| stable_hash(&[node.as_bytes(), key.as_bytes()]))
+        .cloned()
+}
+
+/// Whether a response can be buffered by the owner node: images and manifests,
+/// or videoplayback ranges up to `CLUSTER_MAX_RANGE` bytes.
+pub fn is_bufferable(route: Route, range: Option<&str>) -> bool {
+    let Some(config) = CONFIG.as_ref() else {
+        return false;
+    };
+
+    match route {
+        Route::Image | Route::Hls | Route::Dash => true,
+        Route::Videoplayback => {
+            let Some((start, end)) = range.and_then(|range| range.split_once('-')) else {
+                return false;
+            };
+
+            match (start.parse::<u64>(), end.parse::<u64>()) {
+                (Ok(start), Ok(end)) => end >= start && end - start < config.max_range,
+                _ => false,
+            }
+        }
+        Route::Ump | Route::Other => false,
+    }
+}
+
+/// Fetches the request through the node owning its URL. Returns `None` when this
+/// node is the owner or the owner couldn't be reached, in which case the caller
+/// should go upstream itself.
+pub async fn fetch_from_owner(request: &Request) -> Option<reqwest::Response> {
+    let config = CONFIG.as_ref()?;
+
+    let owner = owner(config, request.url())?;
+    if owner == config.this_node {
+        return None;
+    }
+
+    let mut builder = CLUSTER_CLIENT
+        .request(
+            request.method().clone(),
+            format!("{}/_cluster/fetch", owner),
+        )
+        .headers(request.headers().clone())
+        .header(AUTH_HEADER, &config.secret)
+        .header(URL_HEADER, request.url().as_str());
+
+    if let Some(body) = request.body().and_then(|body| body.as_bytes()) {
+        builder = builder.body(body.to_vec());
+    }
+
+    match builder.send().await {
+        Ok(resp) if resp.headers().contains_key(STATUS_HEADER) => Some(resp),
+        Ok(resp) => {
+            tracing::warn!(
+                "Cluster node {} could not fetch {}: {}",
+                owner,
+                request.url().path(),
+                resp.status()
+            );
+            None
+        }
+        Err(e) => {
+            tracing::warn!(
+                "Cluster node {} is unreachable: {}",
+                owner,
+                crate::logging::redact_error(&e)
+            );
+            None
+        }
+    }
+}
+
+struct Buffered {
+    status: reqwest::StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+type BufferedFuture = Shared<BoxFuture<'static, Result<Arc<Buffered>, String>>>;
+
+static IN_FLIGHT: Lazy<Mutex<HashMap<String, BufferedFuture>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Reads a response body of at most `limit` bytes, so a node never buffers more
+/// than `CLUSTER_MAX_RANGE` for others, whatever it was asked for.
+async fn read_limited(mut resp: reqwest::Response, limit: u64) -> Result<Bytes, String> {
+    let too_large = |len: u64| format!("Response of {} bytes exceeds CLUSTER_MAX_RANGE", len);
+
+    if let Some(len) = resp.content_length().filter(|len| *len > limit) {
+        return Err(too_large(len));
+    }
+
+    let mut body = BytesMut::new();
+    while let Some(chunk) = resp.chunk().await.map_err(|e| e.to_string())? {
+        body.extend_from_slice(&chunk);
+        if body.len() as u64 > limit {
+            return Err(too_large(body.len() as u64));
+        }
+    }
+
+    Ok(body.freeze())
+}
+
+fn is_authorized(config: &Config, req: &HttpRequest) -> bool {
+    let Some(auth) = req.headers().get(AUTH_HEADER) else {
+        return false;
+    };
+
+    utils::constant_time_eq(auth.as_bytes(), config.secret.as_bytes())
+}
+
+async fn health(req: HttpRequest) -> HttpResponse {
+    match CONFIG.as_ref() {
+        Some(config) if is_authorized(config, &req) => HttpResponse::Ok().finish(),
+        _ => HttpResponse::Forbidden().finish(),
+    }
+}
+
+async fn fetch(req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    let Some(config) = CONFIG.as_ref() else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    if !is_authorized(config, &req) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let Some(url) = req
+        .headers()
+        .get(URL_HEADER)
+        .and_then(|url| url.to_str().ok())
+        .and_then(|url| Url::parse(url).ok())
+    else {
+        return HttpResponse::BadRequest().body("No upstream URL provided");
+    };
+
+    if !url.host_str().is_some_and(crate::is_domain_allowed) {
+        return HttpResponse::Forbidden().body("Domain not allowed");
+    }
+
+    let Ok(method) = Method::from_str(req.method().as_str()) else {
+        return HttpResponse::MethodNotAllowed().finish();
+    };
+
+    let mut request = Request::new(method, url);
+
+    let request_headers = request.headers_mut();
+    for (key, value) in req.headers() {
+        if key.as_str().starts_with(HEADER_PREFIX) || !crate::is_header_allowed(key.as_str()) {
+            continue;
+        }
+        if let (Ok(key), Ok(value)) = (
+            reqwest::header::HeaderName::from_bytes(key.as_str().as_bytes()),
+            reqwest::header::HeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            request_headers.insert(key, value);
+        }
+    }
+
+    if let Some(user_agent) = req.headers().get("user-agent") {
+        if let Ok(user_agent) = reqwest::header::HeaderValue::from_bytes(user_agent.as_bytes()) {
+            request_headers.insert("user-agent", user_agent);
+        }
+    }
+
+    if !body.is_empty() {
+        request.body_mut().replace(body.to_vec().into());
+    }
+
+    let key = format!("{} {}", request.method(), normalize_url(request.url()));
+
+    // coalesce concurrent requests for the same object
+    let future = {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        in_flight
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let key = key.clone();
+                async move {
+                    let result = async {
+                        let resp = crate::CLIENT
+                            .execute(request)
+                            .await
+                            .map_err(|e| e.to_string())?;
+                        let status = resp.status();
+                        let headers = resp.headers().clone();
+                        let body = read_limited(resp, config.max_range).await?;
+                        Ok(Arc::new(Buffered {
+                            status,
+                            headers,
+                            body,
+                        }))
+                    }
+                    .await;
+                    IN_FLIGHT.lock().unwrap().remove(&key);
+                    result
+                }
+                .boxed()
+                .shared()
+            })
+            .clone()
+    };
+
+    // keep fetching even if every waiting node goes away
+    tokio::spawn(future.clone());
+
+    match future.await {
+        Ok(buffered) => {
+            let mut response =
+                HttpResponse::build(StatusCode::from_u16(buffered.status.as_u16()).unwrap());
+            for (key, value) in &buffered.headers {
+                if !matches!(
+                    key.as_str(),
+                    "content-length" | "transfer-encoding" | "connection"
+                ) {
+                    response.append_header((key.as_str(), value.as_bytes()));
+                }
+            }
+            response.insert_header((STATUS_HEADER, "ok"));
+            response.body(buffered.body.clone())
+        }
+        Err(e) => HttpResponse::BadGateway().body(e),
+    }
+}
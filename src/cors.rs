@@ -0,0 +1,131 @@
+use crate::{timing, utils};
+use actix_web::{HttpRequest, HttpResponseBuilder};
+use once_cell::sync::Lazy;
+use reqwest::Url;
+use std::env;
+
+struct Config {
+    /// Origins such as `https://piped.video`, or `None` to allow any origin.
+    origins: Option<Vec<String>>,
+    /// Only with an explicit list of origins.
+    credentials: bool,
+    expose_headers: String,
+    max_age: u64,
+    /// Reject requests from pages outside the allowed origins.
+    hotlink_protection: bool,
+}
+
+static CONFIG: Lazy<Config> = Lazy::new(|| {
+    let origins = env::var("CORS_ALLOWED_ORIGINS")
+        .ok()
+        .map(|list| {
+            list.split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .filter(|origins| !origins.is_empty() && !origins.iter().any(|origin| origin == "*"));
+
+    let hotlink_protection = utils::get_env_bool("HOTLINK_PROTECTION");
+    if hotlink_protection && origins.is_none() {
+        tracing::warn!("HOTLINK_PROTECTION has no effect without CORS_ALLOWED_ORIGINS");
+    }
+
+    // reflecting any origin along with credentials would let every site read
+    // responses on behalf of the user
+    let mut credentials = utils::get_env_bool("CORS_ALLOW_CREDENTIALS");
+    if credentials && origins.is_none() {
+        tracing::warn!(
+            "CORS_ALLOW_CREDENTIALS requires CORS_ALLOWED_ORIGINS, allowing any origin without credentials"
+        );
+        credentials = false;
+    }
+
+    Config {
+        origins,
+        credentials,
+        expose_headers: env::var("CORS_EXPOSE_HEADERS").unwrap_or_else(|_| {
+            "Content-Length, Content-Range, Accept-Ranges, X-Request-Id, Server-Timing".to_string()
+        }),
+        max_age: utils::get_env_number("CORS_MAX_AGE", 1728000),
+        hotlink_protection,
+    }
+});
+
+fn is_origin_allowed(origin: &str) -> bool {
+    CONFIG
+        .origins
+        .as_ref()
+        .is_none_or(|origins| origins.iter().any(|allowed| allowed == origin))
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+pub fn add_headers(response: &mut HttpResponseBuilder, req: &HttpRequest) {
+    let origin = header(req, "origin");
+
+    if CONFIG.origins.is_none() {
+        response.append_header(("Access-Control-Allow-Origin", "*"));
+    } else {
+        // the response depends on the origin, caches have to keep them apart
+        response.append_header(("Vary", "Origin"));
+        if let Some(origin) = origin.filter(|origin| is_origin_allowed(origin)) {
+            response.append_header(("Access-Control-Allow-Origin", origin));
+        }
+    }
+
+    // lets pages read the Server-Timing header through the Resource Timing API
+    if timing::is_enabled() {
+        if CONFIG.origins.is_none() {
+            response.append_header(("Timing-Allow-Origin", "*"));
+        } else if let Some(origin) = origin.filter(|origin| is_origin_allowed(origin)) {
+            response.append_header(("Timing-Allow-Origin", origin));
+        }
+    }
+
+    // wildcards are taken literally in credentialed requests
+    if CONFIG.credentials {
+        response
+            .append_header(("Access-Control-Allow-Credentials", "true"))
+            .append_header(("Access-Control-Allow-Methods", "GET, HEAD, OPTIONS"));
+        if let Some(headers) = header(req, "access-control-request-headers") {
+            response.append_header(("Access-Control-Allow-Headers", headers));
+        }
+    } else {
+        response
+            .append_header(("Access-Control-Allow-Headers", "*"))
+            .append_header(("Access-Control-Allow-Methods", "*"));
+    }
+
+    response
+        .append_header((
+            "Access-Control-Expose-Headers",
+            CONFIG.expose_headers.as_str(),
+        ))
+        .append_header(("Access-Control-Max-Age", CONFIG.max_age.to_string()));
+}
+
+/// Hotlink protection: requests sent from a page must come from an allowed
+/// origin. Native apps send neither `Origin` nor `Referer` and are let through.
+pub fn is_request_allowed(req: &HttpRequest) -> bool {
+    if !CONFIG.hotlink_protection || CONFIG.origins.is_none() {
+        return true;
+    }
+
+    if let Some(origin) = header(req, "origin") {
+        return is_origin_allowed(origin);
+    }
+
+    match req.headers().get("referer") {
+        Some(referer) => referer
+            .to_str()
+            .ok()
+            .and_then(|referer| Url::parse(referer).ok())
+            .is_some_and(|referer| is_origin_allowed(&referer.origin().ascii_serialization())),
+        None => true,
+    }
+}
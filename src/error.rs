@@ -0,0 +1,170 @@
+use crate::logging;
+use crate::ssrf::BlockedDestination;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, HttpResponseBuilder, ResponseError};
+use serde_json::json;
+use std::error::Error;
+use std::fmt;
+
+/// Everything that can go wrong while proxying a request. Each variant maps to
+/// a status code and a machine-readable code sent in a small JSON body.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The client, its origin or its API key isn't let in.
+    Forbidden(&'static str),
+    /// The client has to wait this many seconds before it's let in again.
+    RateLimited {
+        retry_after: u64,
+    },
+    QuotaExceeded,
+    MissingHost,
+    InvalidHost,
+    DomainNotAllowed,
+    #[cfg_attr(not(feature = "qhash"), allow(dead_code))]
+    InvalidSignature(&'static str),
+    #[cfg_attr(not(feature = "tokens"), allow(dead_code))]
+    InvalidToken(&'static str),
+    /// A query parameter or header of the request couldn't be parsed.
+    InvalidParameter(&'static str),
+    Expired,
+    /// The content has this many bytes, none of which the range selects.
+    RangeNotSatisfiable(u64),
+    Upstream(reqwest::Error),
+    Internal(&'static str),
+}
+
+impl ProxyError {
+    /// Logs the details of failures on our or upstream's side, which are kept
+    /// out of the response.
+    pub fn log(&self) {
+        match self {
+            ProxyError::Upstream(e) => {
+                tracing::warn!("Upstream request failed: {}", logging::redact_error(e))
+            }
+            ProxyError::Internal(message) => tracing::error!("Internal error: {}", message),
+            _ => {}
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProxyError::Forbidden(_) => "forbidden",
+            ProxyError::RateLimited { .. } => "rate_limited",
+            ProxyError::QuotaExceeded => "quota_exceeded",
+            ProxyError::MissingHost => "missing_host",
+            ProxyError::InvalidHost => "invalid_host",
+            ProxyError::DomainNotAllowed => "domain_not_allowed",
+            ProxyError::InvalidSignature(_) => "invalid_signature",
+            ProxyError::InvalidToken(_) => "invalid_token",
+            ProxyError::InvalidParameter(_) => "invalid_parameter",
+            ProxyError::Expired => "expired",
+            ProxyError::RangeNotSatisfiable(_) => "range_not_satisfiable",
+            ProxyError::Upstream(e) if e.is_timeout() => "upstream_timeout",
+            ProxyError::Upstream(e) if e.is_redirect() => "redirect_not_allowed",
+            ProxyError::Upstream(e) if is_blocked_destination(e) => "blocked_destination",
+            ProxyError::Upstream(_) => "upstream_error",
+            ProxyError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+/// Whether the SSRF guard refused the connection.
+fn is_blocked_destination(error: &reqwest::Error) -> bool {
+    let mut source = error.source();
+    while let Some(error) = source {
+        if error.is::<BlockedDestination>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::Forbidden(message) => write!(f, "{}", message),
+            ProxyError::RateLimited { .. } => write!(f, "Too many requests"),
+            ProxyError::QuotaExceeded => write!(f, "API key quota exceeded"),
+            ProxyError::MissingHost => write!(f, "No host provided"),
+            ProxyError::InvalidHost => write!(f, "Invalid host provided"),
+            ProxyError::DomainNotAllowed => write!(f, "Domain not allowed"),
+            ProxyError::InvalidSignature(message) | ProxyError::InvalidToken(message) => {
+                write!(f, "{}", message)
+            }
+            ProxyError::InvalidParameter(name) => write!(f, "Invalid {} provided", name),
+            ProxyError::Expired => write!(f, "Expire time in past"),
+            ProxyError::RangeNotSatisfiable(_) => write!(f, "Range not satisfiable"),
+            ProxyError::Upstream(e) if e.is_timeout() => write!(f, "Upstream timed out"),
+            ProxyError::Upstream(_) => write!(f, "Upstream request failed"),
+            ProxyError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for ProxyError {}
+
+impl From<reqwest::Error> for ProxyError {
+    fn from(e: reqwest::Error) -> Self {
+        ProxyError::Upstream(e)
+    }
+}
+
+impl ResponseError for ProxyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ProxyError::MissingHost | ProxyError::InvalidHost | ProxyError::InvalidParameter(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ProxyError::Forbidden(_)
+            | ProxyError::DomainNotAllowed
+            | ProxyError::InvalidSignature(_)
+            | ProxyError::InvalidToken(_) => StatusCode::FORBIDDEN,
+            ProxyError::RateLimited { .. } | ProxyError::QuotaExceeded => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            ProxyError::Expired => StatusCode::GONE,
+            ProxyError::RangeNotSatisfiable(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            ProxyError::Upstream(e) if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.respond(&mut HttpResponse::build(self.status_code()), None)
+    }
+}
+
+impl ProxyError {
+    /// Finishes a response started with the status code of the error, telling
+    /// the id of the request to quote when reporting it.
+    pub fn respond(
+        &self,
+        response: &mut HttpResponseBuilder,
+        request_id: Option<&str>,
+    ) -> HttpResponse {
+        match self {
+            ProxyError::RangeNotSatisfiable(total) => {
+                response.insert_header((
+                    "Content-Range",
+                    crate::range::unsatisfied_content_range(*total),
+                ));
+            }
+            ProxyError::RateLimited { retry_after } => {
+                response.insert_header(("Retry-After", retry_after.to_string()));
+            }
+            _ => {}
+        }
+
+        let mut body = json!({
+            "error": self.code(),
+            "message": self.to_string(),
+        });
+        if let Some(request_id) = request_id {
+            body["request_id"] = request_id.into();
+        }
+
+        response.json(body)
+    }
+}
@@ -0,0 +1,187 @@
+use crate::utils;
+use actix_server::ServerHandle;
+use actix_web::{web, HttpResponse};
+use once_cell::sync::Lazy;
+use reqwest::Url;
+use serde_json::{json, Map, Value};
+use std::env;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Set once a SIGTERM arrived, while requests in flight are drained.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// How long to stay up reporting not ready after a SIGTERM, so load balancers
+/// stop sending requests before the listeners close.
+static DRAIN_PERIOD: Lazy<Duration> =
+    Lazy::new(|| Duration::from_secs(utils::get_env_number("SHUTDOWN_DRAIN_SECONDS", 0)));
+
+const PROXY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Host and port of the upstream proxy set in `PROXY`.
+static PROXY_ADDR: Lazy<Option<(String, u16)>> = Lazy::new(|| {
+    let proxy = Url::parse(&env::var("PROXY").ok()?).ok()?;
+    let port = proxy.port_or_known_default().unwrap_or(1080);
+    Some((proxy.host_str()?.to_string(), port))
+});
+
+static TRANSCODING: Lazy<bool> = Lazy::new(|| {
+    cfg!(any(feature = "webp", feature = "avif"))
+        && !utils::get_env_bool("DISALLOW_IMAGE_TRANSCODING")
+});
+
+/// Number of concurrent image transcodes above which we stop taking requests.
+static MAX_TRANSCODES: Lazy<usize> = Lazy::new(|| {
+    let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    utils::get_env_number("READY_MAX_TRANSCODES", cpus)
+});
+
+static TRANSCODES: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts an image transcode as running until dropped.
+pub struct TranscodeSlot;
+
+impl TranscodeSlot {
+    #[cfg_attr(not(any(feature = "webp", feature = "avif")), allow(dead_code))]
+    pub fn acquire() -> Self {
+        TRANSCODES.fetch_add(1, Ordering::Relaxed);
+        TranscodeSlot
+    }
+}
+
+impl Drop for TranscodeSlot {
+    fn drop(&mut self) {
+        TRANSCODES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Only liveness, for the public listeners. Readiness tells about the upstream
+/// proxy and load, so it is kept to the admin listener.
+pub fn configure_public(cfg: &mut web::ServiceConfig) {
+    cfg.route("/healthz", web::get().to(live));
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/healthz", web::get().to(live))
+        .route("/readyz", web::get().to(ready));
+}
+
+/// Stops the server on signals like actix would, except that a SIGTERM first
+/// marks us as not ready for `SHUTDOWN_DRAIN_SECONDS`.
+pub fn handle_signals(server: ServerHandle) {
+    actix_web::rt::spawn(async move {
+        let (Ok(mut term), Ok(mut int), Ok(mut quit)) = (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::interrupt()),
+            signal(SignalKind::quit()),
+        ) else {
+            tracing::error!("Could not listen for signals");
+            return;
+        };
+
+        let graceful = tokio::select! {
+            _ = term.recv() => true,
+            _ = int.recv() => false,
+            _ = quit.recv() => false,
+        };
+
+        if graceful && !DRAIN_PERIOD.is_zero() {
+            DRAINING.store(true, Ordering::Relaxed);
+            tracing::info!(
+                "Draining for {} seconds before shutting down",
+                DRAIN_PERIOD.as_secs()
+            );
+            actix_web::rt::time::sleep(*DRAIN_PERIOD).await;
+        }
+
+        server.stop(graceful).await;
+    });
+}
+
+struct Check {
+    name: &'static str,
+    ok: bool,
+    detail: String,
+}
+
+fn check_draining() -> Check {
+    let draining = DRAINING.load(Ordering::Relaxed);
+    Check {
+        name: "shutdown",
+        ok: !draining,
+        detail: if draining { "draining" } else { "running" }.to_string(),
+    }
+}
+
+async fn check_proxy(host: &str, port: u16) -> Check {
+    let result = tokio::time::timeout(PROXY_TIMEOUT, TcpStream::connect((host, port))).await;
+    let (ok, detail) = match result {
+        Ok(Ok(_)) => (true, format!("{}:{} reachable", host, port)),
+        Ok(Err(e)) => (false, format!("{}:{} unreachable: {}", host, port, e)),
+        Err(_) => (false, format!("{}:{} timed out", host, port)),
+    };
+
+    Check {
+        name: "upstream_proxy",
+        ok,
+        detail,
+    }
+}
+
+fn check_transcodes() -> Check {
+    let running = TRANSCODES.load(Ordering::Relaxed);
+    Check {
+        name: "transcode_capacity",
+        ok: running < *MAX_TRANSCODES,
+        detail: format!("{} of {} transcodes running", running, *MAX_TRANSCODES),
+    }
+}
+
+fn respond(ready: bool, body: Value) -> HttpResponse {
+    let mut response = if ready {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+
+    response
+        .insert_header(("Cache-Control", "no-store"))
+        .json(body)
+}
+
+/// Liveness, answered as long as the workers are.
+async fn live() -> HttpResponse {
+    respond(true, json!({ "status": "ok" }))
+}
+
+/// Readiness, with the outcome of every check.
+async fn ready() -> HttpResponse {
+    let mut checks = vec![check_draining()];
+    if let Some((host, port)) = PROXY_ADDR.as_ref() {
+        checks.push(check_proxy(host, *port).await);
+    }
+    if *TRANSCODING {
+        checks.push(check_transcodes());
+    }
+
+    let ready = checks.iter().all(|check| check.ok);
+    let details = checks
+        .into_iter()
+        .map(|check| {
+            (
+                check.name.to_string(),
+                json!({ "ok": check.ok, "detail": check.detail }),
+            )
+        })
+        .collect::<Map<_, _>>();
+
+    respond(
+        ready,
+        json!({
+            "status": if ready { "ready" } else { "not_ready" },
+            "checks": details,
+        }),
+    )
+}
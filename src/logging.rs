@@ -0,0 +1,237 @@
+use crate::api_keys;
+use actix_web::{HttpRequest, HttpResponse};
+use once_cell::sync::Lazy;
+use reqwest::Url;
+use std::env;
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, IsTerminal};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::field::Empty;
+use tracing::instrument::Instrumented;
+use tracing::{Instrument, Span};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+/// Query parameters that are credentials and never logged.
+const SECRET_PARAMS: [&str; 1] = [api_keys::QUERY_PARAM];
+
+/// Query parameters that authorize a URL, left out of logs in privacy mode.
+const SIGNATURE_PARAMS: [&str; 5] = ["sig", "lsig", "signature", "qhash", "qh2"];
+
+/// How client IPs and signed URLs end up in the logs, set with `LOG_PRIVACY`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Privacy {
+    Off,
+    /// IPv4 addresses are cut to their /24, IPv6 addresses to their /48.
+    Truncate,
+    /// Addresses are replaced by a hash, keyed per process so it can't be
+    /// reversed by hashing every address.
+    Hash,
+}
+
+static PRIVACY: Lazy<Privacy> = Lazy::new(|| match env::var("LOG_PRIVACY").as_deref() {
+    Ok("truncate") => Privacy::Truncate,
+    Ok("hash") => Privacy::Hash,
+    _ => Privacy::Off,
+});
+
+static HASHER: Lazy<RandomState> = Lazy::new(RandomState::new);
+
+/// Sets up the subscriber. `LOG_FORMAT` picks `json` or `compact` output, and
+/// `LOG_LEVEL` (or `RUST_LOG`) takes `info` or a filter such as
+/// `warn,piped_proxy=debug`.
+pub fn init() {
+    let filter = env::var("LOG_LEVEL")
+        .or_else(|_| env::var("RUST_LOG"))
+        .ok()
+        .and_then(|filter| EnvFilter::try_new(filter).ok())
+        .unwrap_or_else(|| EnvFilter::new("info"));
+
+    let ansi = io::stdout().is_terminal();
+    let (json, compact) = if env::var("LOG_FORMAT").as_deref() == Ok("json") {
+        (Some(fmt::layer().json().with_current_span(true)), None)
+    } else {
+        (None, Some(fmt::layer().compact().with_ansi(ansi)))
+    };
+
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(json)
+        .with(compact);
+
+    #[cfg(feature = "otel")]
+    let registry = registry.with(crate::otel::layer());
+
+    registry.init();
+
+    if let Ok(privacy) = env::var("LOG_PRIVACY") {
+        if *PRIVACY == Privacy::Off && privacy != "off" {
+            tracing::warn!("Unknown LOG_PRIVACY {}, logging client IPs as is", privacy);
+        }
+    }
+}
+
+/// A client IP as it may be logged.
+pub fn client(ip: Option<IpAddr>) -> String {
+    let Some(ip) = ip.map(|ip| ip.to_canonical()) else {
+        return "-".to_string();
+    };
+
+    match *PRIVACY {
+        Privacy::Off => ip.to_string(),
+        Privacy::Truncate => match ip {
+            IpAddr::V4(ip) => {
+                let [a, b, c, _] = ip.octets();
+                Ipv4Addr::new(a, b, c, 0).to_string()
+            }
+            IpAddr::V6(ip) => {
+                let [a, b, c, ..] = ip.segments();
+                Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0).to_string()
+            }
+        },
+        Privacy::Hash => format!("{:016x}", HASHER.hash_one(ip)),
+    }
+}
+
+/// A URL as it may be logged, without credentials and, in privacy mode,
+/// without signatures.
+pub fn redact_url(url: &Url) -> String {
+    let redacted = |key: &str| {
+        SECRET_PARAMS.contains(&key)
+            || (*PRIVACY != Privacy::Off && SIGNATURE_PARAMS.contains(&key))
+    };
+
+    if !url.query_pairs().any(|(key, _)| redacted(&key)) {
+        return url.to_string();
+    }
+
+    let mut url = url.clone();
+    let pairs = url
+        .query_pairs()
+        .filter(|(key, _)| !redacted(key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    url.query_pairs_mut().clear().extend_pairs(pairs);
+
+    url.to_string()
+}
+
+/// The message of an upstream error with its URL redacted.
+pub fn redact_error(error: &reqwest::Error) -> String {
+    let message = error.to_string();
+    match error.url() {
+        Some(url) => message.replace(url.as_str(), &redact_url(url)),
+        None => message,
+    }
+}
+
+/// The `X-Request-Id` of the request, or a new one if it has none. Ids given by
+/// clients end up in logs and headers, so only short, plain ones are taken.
+pub fn request_id(req: &HttpRequest) -> String {
+    req.headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| {
+            (1..=128).contains(&id.len())
+                && id
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte))
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", fastrand::u64(..)))
+}
+
+/// The span of a request. Its route, host, itag and status are recorded while
+/// it is handled, bytes and duration once the response is sent.
+pub fn request_span(req: &HttpRequest, id: &str, client_ip: Option<IpAddr>) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        id,
+        method = %req.method(),
+        client = %client(client_ip),
+        route = Empty,
+        host = Empty,
+        itag = Empty,
+        key_version = Empty,
+        status = Empty,
+        bytes = Empty,
+        duration_ms = Empty,
+    );
+
+    #[cfg(feature = "otel")]
+    crate::otel::set_parent(&span, req.headers());
+
+    span
+}
+
+/// Logs the completion of a request with the fields recorded on its span.
+fn completed(span: &Span, started: Instant) {
+    span.record("duration_ms", started.elapsed().as_millis() as u64);
+    tracing::info!(parent: span, "request completed");
+}
+
+/// Counts the bytes sent and logs the completion of the request when dropped.
+struct Completion {
+    span: Span,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        self.span.record("bytes", self.bytes);
+        completed(&self.span, self.started);
+    }
+}
+
+/// Logs the request once its response body has been sent, or dropped because
+/// the client went away.
+pub fn track(response: HttpResponse, span: Span, started: Instant) -> HttpResponse {
+    let mut completion = Completion {
+        span,
+        started,
+        bytes: 0,
+    };
+
+    crate::utils::count_body(response, move |len| {
+        let completion = &mut completion;
+        completion.bytes += len as u64;
+    })
+}
+
+/// Wraps the connector of the upstream client, so establishing a connection,
+/// from resolving the host to the TLS handshake, gets a span of its own.
+#[derive(Clone)]
+pub struct ConnectSpanLayer;
+
+impl<S> Layer<S> for ConnectSpanLayer {
+    type Service = ConnectSpan<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConnectSpan(inner)
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectSpan<S>(S);
+
+impl<S: Service<R>, R> Service<R> for ConnectSpan<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Instrumented<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        self.0
+            .call(req)
+            .instrument(tracing::info_span!("upstream_connect"))
+    }
+}
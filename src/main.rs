@@ -1,29 +1,76 @@
+mod access;
+mod api_keys;
+mod cidr;
+mod client_ip;
+mod cluster;
+mod cors;
+mod error;
+mod health;
+mod logging;
+mod metrics;
+#[cfg(feature = "otel")]
+mod otel;
+mod prefetch;
+mod proxy_protocol;
+#[cfg(feature = "qhash")]
+mod qhash;
+mod range;
+mod ratelimit;
+mod refresh;
+mod ssrf;
+mod streams;
+#[cfg(test)]
+mod test_server;
+mod throttle;
+mod timing;
+#[cfg(feature = "tokens")]
+mod token;
 mod ump_stream;
 mod utils;
 
-use actix_web::http::StatusCode;
-use actix_web::{web, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer};
+use actix_http::error::DispatchError;
+use actix_http::{HttpService, Protocol};
+use actix_server::Server;
+use actix_service::{
+    fn_service, map_config, IntoServiceFactory, ServiceFactory, ServiceFactoryExt,
+};
+use actix_web::dev::AppConfig;
+use actix_web::http::{header, StatusCode};
+use actix_web::rt::net::{TcpStream, UnixStream};
+use actix_web::{
+    web, App, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, ResponseError,
+};
 use listenfd::ListenFd;
 use once_cell::sync::Lazy;
+use proxy_protocol::ProxiedStream;
 use qstring::QString;
 use regex::Regex;
 use reqwest::{Body, Client, Request, Url};
-use std::error::Error;
-use std::io::ErrorKind;
-use std::net::TcpListener;
+use std::net::{IpAddr, SocketAddr, TcpListener};
 use std::os::unix::net::UnixListener;
 use std::str::FromStr;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use std::{env, io};
 
 #[cfg(not(any(feature = "reqwest-native-tls", feature = "reqwest-rustls")))]
 compile_error!("feature \"reqwest-native-tls\" or \"reqwest-rustls\" must be set for proxy to have TLS support");
 
+use api_keys::Auth;
+use client_ip::ClientIp;
+use error::ProxyError;
 use futures_util::TryStreamExt;
 use http::{HeaderName, Method};
-use reqwest::header::HeaderValue;
+use metrics::{Egress, Route};
+use range::{ByteRange, RangeSpec};
+use ratelimit::LimitClass;
+use reqwest::header::{HeaderValue, LOCATION};
+use throttle::ThrottledStream;
+use timing::Phase;
+use tokio::io::{AsyncRead, AsyncWrite};
 #[cfg(any(feature = "webp", feature = "avif", feature = "qhash"))]
 use tokio::task::spawn_blocking;
+use tracing::field::Empty;
+use tracing::{info, info_span, warn, Instrument};
 use ump_stream::UmpTransformStream;
 
 #[cfg(feature = "mimalloc")]
@@ -35,18 +82,18 @@ fn try_get_fd_listeners() -> (Option<UnixListener>, Option<TcpListener>) {
 
     let unix_listener = env::var("FD_UNIX").ok().map(|fd_unix| {
         let fd_pos = fd_unix.parse().expect("FD_UNIX is not a number");
-        println!("Trying to take Unix socket at position {}", fd_pos);
+        info!("Trying to take Unix socket at position {}", fd_pos);
         fd.take_unix_listener(fd_pos)
-            .expect(format!("fd {} is not a Unix socket", fd_pos).as_str())
-            .expect(format!("fd {} has already been used", fd_pos).as_str())
+            .unwrap_or_else(|_| panic!("fd {} is not a Unix socket", fd_pos))
+            .unwrap_or_else(|| panic!("fd {} has already been used", fd_pos))
     });
 
     let tcp_listener = env::var("FD_TCP").ok().map(|fd_tcp| {
         let fd_pos = fd_tcp.parse().expect("FD_TCP is not a number");
-        println!("Trying to take TCP listener at position {}", fd_pos);
+        info!("Trying to take TCP listener at position {}", fd_pos);
         fd.take_tcp_listener(fd_pos)
-            .expect(format!("fd {} is not a TCP listener", fd_pos).as_str())
-            .expect(format!("fd {} has already been used", fd_pos).as_str())
+            .unwrap_or_else(|_| panic!("fd {} is not a TCP listener", fd_pos))
+            .unwrap_or_else(|| panic!("fd {} has already been used", fd_pos))
     });
 
     (unix_listener, tcp_listener)
@@ -54,44 +101,168 @@ fn try_get_fd_listeners() -> (Option<UnixListener>, Option<TcpListener>) {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    println!("Running server!");
+    logging::init();
+    info!("Running server!");
 
-    let mut server = HttpServer::new(|| {
-        // match all requests
-        App::new().default_service(web::to(index))
-    });
+    #[cfg(feature = "qhash")]
+    if qhash::is_enabled() {
+        info!("Accepting {} qhash key version(s)", qhash::key_count());
+    }
+
+    cluster::spawn_health_checks();
+    access::init();
+    api_keys::init();
+
+    // the health module drains on SIGTERM before stopping
+    let mut server = Server::build().disable_signals();
+    let mut listening = false;
 
     let fd_listeners = try_get_fd_listeners();
 
     if let Some(unix_listener) = fd_listeners.0 {
+        let proxy_protocol = uses_proxy_protocol("fd_unix");
         server = server
-            .listen_uds(unix_listener)
+            .listen_uds("fd-unix", unix_listener, move || {
+                uds_service(proxy_protocol)
+            })
             .expect("Error while trying to listen on Unix socket passed by fd");
-        println!("Listening on Unix socket passed by fd.");
+        listening = true;
+        info!("Listening on Unix socket passed by fd.");
     }
 
     if let Some(tcp_listener) = fd_listeners.1 {
+        let proxy_protocol = uses_proxy_protocol("fd_tcp");
         server = server
-            .listen(tcp_listener)
+            .listen("fd-tcp", tcp_listener, move || tcp_service(proxy_protocol))
             .expect("Error while trying to listen on TCP listener passed by fd");
-        println!("Listening on TCP listener passed by fd.");
+        listening = true;
+        info!("Listening on TCP listener passed by fd.");
     }
 
     // Only bind manually if there is not already a listener
-    if server.addrs().is_empty() {
+    if !listening {
         // get socket/port from env
         // backwards compat when only UDS is set
         server = if utils::get_env_bool("UDS") {
             let socket_path =
                 env::var("BIND_UNIX").unwrap_or_else(|_| "./socket/actix.sock".to_string());
-            server.bind_uds(socket_path)?
+            let proxy_protocol = uses_proxy_protocol("unix");
+            server.bind_uds("unix", socket_path, move || uds_service(proxy_protocol))?
         } else {
             let bind = env::var("BIND").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
-            server.bind(bind)?
+            let proxy_protocol = uses_proxy_protocol("tcp");
+            server.bind("tcp", bind, move || tcp_service(proxy_protocol))?
         };
     }
 
-    server.run().await
+    // metrics and such, kept off the public listeners
+    if let Ok(admin_bind) = env::var("ADMIN_BIND") {
+        server = server.bind("admin", &admin_bind, admin_service)?;
+        info!("Admin listener on {}", admin_bind);
+    }
+
+    let server = server.run();
+    health::handle_signals(server.handle());
+    server.await?;
+
+    #[cfg(feature = "otel")]
+    tokio::task::spawn_blocking(otel::shutdown).await?;
+
+    Ok(())
+}
+
+/// Whether the listener is named in `PROXY_PROTOCOL`, a comma separated list of
+/// `tcp`, `unix`, `fd_tcp` and `fd_unix`.
+fn uses_proxy_protocol(listener: &str) -> bool {
+    env::var("PROXY_PROTOCOL").is_ok_and(|listeners| {
+        listeners
+            .split(',')
+            .any(|name| name.trim().eq_ignore_ascii_case(listener))
+    })
+}
+
+fn http_service<T>() -> impl ServiceFactory<
+    (T, Protocol, Option<SocketAddr>),
+    Config = (),
+    Response = (),
+    Error = DispatchError,
+    InitError = (),
+>
+where
+    T: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    // match all requests
+    let app = App::new()
+        .configure(cluster::configure)
+        .configure(health::configure_public)
+        .default_service(web::to(index))
+        .into_factory()
+        .map_err(|err| err.error_response());
+
+    HttpService::build()
+        .client_disconnect_timeout(Duration::from_secs(1))
+        .finish(map_config(app, |_| AppConfig::default()))
+}
+
+fn admin_service(
+) -> impl ServiceFactory<TcpStream, Config = (), Response = (), Error = DispatchError, InitError = ()>
+{
+    let app = App::new()
+        .route("/metrics", web::get().to(metrics::handler))
+        .configure(health::configure)
+        .configure(streams::configure)
+        .into_factory()
+        .map_err(|err| err.error_response());
+
+    HttpService::build()
+        .finish(map_config(app, |_| AppConfig::default()))
+        .tcp()
+}
+
+fn tcp_service(
+    proxy_protocol: bool,
+) -> impl ServiceFactory<TcpStream, Config = (), Response = (), Error = DispatchError, InitError = ()>
+{
+    fn_service(move |io: TcpStream| async move {
+        let peer_addr = io.peer_addr().ok();
+
+        if !proxy_protocol {
+            return Ok((ProxiedStream::plain(io), Protocol::Http1, peer_addr));
+        }
+
+        match proxy_protocol::accept(io).await {
+            Ok((io, source)) => Ok((io, Protocol::Http1, source.or(peer_addr))),
+            Err(e) => {
+                warn!(
+                    "Rejected connection from {}: {}",
+                    logging::client(peer_addr.map(|addr| addr.ip())),
+                    e
+                );
+                Err(DispatchError::Io(e))
+            }
+        }
+    })
+    .and_then(http_service())
+}
+
+fn uds_service(
+    proxy_protocol: bool,
+) -> impl ServiceFactory<UnixStream, Config = (), Response = (), Error = DispatchError, InitError = ()>
+{
+    fn_service(move |io: UnixStream| async move {
+        if !proxy_protocol {
+            return Ok((ProxiedStream::plain(io), Protocol::Http1, None));
+        }
+
+        match proxy_protocol::accept(io).await {
+            Ok((io, source)) => Ok((io, Protocol::Http1, source)),
+            Err(e) => {
+                warn!("Rejected connection on Unix socket: {}", e);
+                Err(DispatchError::Io(e))
+            }
+        }
+    })
+    .and_then(http_service())
 }
 
 static RE_DOMAIN: Lazy<Regex> =
@@ -122,6 +293,13 @@ static CLIENT: Lazy<Client> = Lazy::new(|| {
         builder
     };
 
+    let builder = builder
+        .redirect(redirect_policy())
+        .dns_resolver(std::sync::Arc::new(ssrf::GuardedResolver::new(
+            env::var("PROXY").ok().as_deref(),
+        )))
+        .connector_layer(logging::ConnectSpanLayer);
+
     if utils::get_env_bool("IPV4_ONLY") {
         builder.local_address("0.0.0.0".parse().ok())
     } else {
@@ -131,6 +309,39 @@ static CLIENT: Lazy<Client> = Lazy::new(|| {
     .unwrap()
 });
 
+/// Instead of following upstream redirects, hand them to the client with the
+/// `Location` rewritten to go through the proxy.
+static REWRITE_REDIRECTS: Lazy<bool> = Lazy::new(|| utils::get_env_bool("REWRITE_REDIRECTS"));
+
+const MAX_REDIRECTS: usize = 10;
+
+fn is_redirect_allowed(url: &Url) -> bool {
+    url.scheme() == "https" && url.host_str().is_some_and(is_domain_allowed)
+}
+
+/// Follows redirects only as long as every hop stays on an allowed domain.
+fn redirect_policy() -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(|attempt| {
+        if *REWRITE_REDIRECTS {
+            return attempt.stop();
+        }
+
+        if attempt.previous().len() > MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+
+        if is_redirect_allowed(attempt.url()) {
+            attempt.follow()
+        } else {
+            warn!(
+                "Refusing to follow redirect to {}",
+                attempt.url().host_str().unwrap_or_default()
+            );
+            attempt.error("redirect to a domain that is not allowed")
+        }
+    })
+}
+
 const ANDROID_USER_AGENT: &str = "com.google.android.youtube/1537338816 (Linux; U; Android 13; en_US; ; Build/TQ2A.230505.002; Cronet/113.0.5672.24)";
 const ALLOWED_DOMAINS: [&str; 8] = [
     "youtube.com",
@@ -143,16 +354,14 @@ const ALLOWED_DOMAINS: [&str; 8] = [
     "ajay.app",
 ];
 
-fn add_headers(response: &mut HttpResponseBuilder) {
-    response
-        .append_header(("Access-Control-Allow-Origin", "*"))
-        .append_header(("Access-Control-Allow-Headers", "*"))
-        .append_header(("Access-Control-Allow-Methods", "*"))
-        .append_header(("Access-Control-Max-Age", "1728000"));
+fn is_domain_allowed(host: &str) -> bool {
+    RE_DOMAIN
+        .captures(host)
+        .is_some_and(|domain| ALLOWED_DOMAINS.contains(&domain.get(1).unwrap().as_str()))
 }
 
 fn is_header_allowed(header: &str) -> bool {
-    if header.starts_with("access-control") {
+    if header.starts_with("access-control") || header.starts_with(cluster::HEADER_PREFIX) {
         return false;
     }
 
@@ -169,6 +378,7 @@ fn is_header_allowed(header: &str) -> bool {
             | "range"
             | "transfer-encoding"
             | "x-real-ip"
+            | api_keys::HEADER
             | "origin"
             | "referer"
             // the 'x-title' header contains non-ascii characters which is not allowed on some HTTP clients
@@ -176,49 +386,54 @@ fn is_header_allowed(header: &str) -> bool {
     )
 }
 
-struct RangeRequest {
-    start: u64,
-    end: u64,
-    total_size: u64,
-}
-
-fn parse_range(range_str: &str, total_size: u64) -> Option<RangeRequest> {
-    let range_parts: Vec<&str> = range_str.split('-').collect();
-
-    if range_parts.len() != 2 {
-        return None;
+/// Check that the upstream URL is still valid before answering HEAD requests
+/// for `/videoplayback` from its query.
+static VERIFY_HEAD: Lazy<bool> = Lazy::new(|| utils::get_env_bool("VERIFY_HEAD_UPSTREAM"));
+
+/// Answers a HEAD request for `/videoplayback` from the `clen`, `mime` and `dur`
+/// query parameters.
+fn head_response(
+    req: &HttpRequest,
+    clen: u64,
+    mime_type: Option<&str>,
+    duration: Option<&str>,
+    range: Option<&str>,
+) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    cors::add_headers(&mut response, req);
+    response.insert_header(("Accept-Ranges", "bytes"));
+
+    if let Some(mime_type) = mime_type {
+        response.content_type(mime_type);
+    }
+    if let Some(duration) = duration {
+        response.insert_header(("X-Content-Duration", duration));
     }
 
-    let start_result = range_parts[0].parse::<u64>();
-    let start = start_result.unwrap_or(0);
-
-    // Parse end position - if empty, use total_size-1 (open-ended range)
-    let end = if range_parts[1].is_empty() {
-        total_size.saturating_sub(1) // Avoid underflow
-    } else {
-        let end_result = range_parts[1].parse::<u64>();
-        let parsed_end = end_result.unwrap_or(total_size.saturating_sub(1));
-        parsed_end.min(total_size.saturating_sub(1))
+    let length = match range.and_then(|range| ByteRange::from_query(range, clen)) {
+        Some(range) => {
+            response.status(StatusCode::PARTIAL_CONTENT);
+            response.insert_header(("Content-Range", range.content_range(clen)));
+            range.len()
+        }
+        None => clen,
     };
 
-    Some(RangeRequest {
-        start,
-        end,
-        total_size,
-    })
+    // the body is never sent, the stream only keeps the Content-Length
+    response
+        .no_chunking(length)
+        .streaming(futures_util::stream::empty::<Result<bytes::Bytes, io::Error>>())
 }
 
 fn handle_range_response_correction(
     response: &mut HttpResponseBuilder,
     range_str: Option<&String>,
+    partial: bool,
     clen: Option<u64>,
     resp: &reqwest::Response,
 ) -> Option<()> {
-    // Check if this is a range request (either in headers or query string)
-    let has_range_request = resp.headers().contains_key("range") || range_str.is_some();
-
-    // Only apply correction if we have a range request and response is 200 (should be 206)
-    if !has_range_request
+    // Only apply correction if the client asked for a range and the response is 200 (should be 206)
+    if !partial
         || !resp.status().is_success()
         || resp.status() == reqwest::StatusCode::PARTIAL_CONTENT
     {
@@ -239,124 +454,166 @@ fn handle_range_response_correction(
             .ok()?,
     };
 
-    if total_size == 0 {
-        return None;
-    }
-
-    // Parse the range request into start/end positions
-    let range_request = parse_range(range_str?, total_size)?;
+    let range = ByteRange::from_query(range_str?, total_size)?;
 
-    // Apply proper HTTP range headers
-    apply_range_headers(response, &range_request);
+    // Set proper partial content response
+    response.status(actix_web::http::StatusCode::PARTIAL_CONTENT);
+    // Set required headers for partial content responses
+    response.insert_header(("Content-Range", range.content_range(total_size)));
+    response.insert_header(("Content-Length", range.len().to_string()));
 
     Some(())
 }
 
-fn apply_range_headers(response: &mut HttpResponseBuilder, range_request: &RangeRequest) {
-    let content_range_value = format!(
-        "bytes {}-{}/{}",
-        range_request.start, range_request.end, range_request.total_size
-    );
+async fn index(req: HttpRequest, client_ip: ClientIp) -> HttpResponse {
+    let started = Instant::now();
+    let request_id = logging::request_id(&req);
+    let span = logging::request_span(&req, &request_id, client_ip.0);
+    let result = handle(req.clone(), client_ip)
+        .instrument(span.clone())
+        .await;
+
+    let mut response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            span.in_scope(|| e.log());
+            let mut response = HttpResponse::build(e.status_code());
+            cors::add_headers(&mut response, &req);
+            e.respond(&mut response, Some(&request_id))
+        }
+    };
+
+    // the route is known once the request has been parsed
+    let route = req
+        .extensions()
+        .get::<Route>()
+        .copied()
+        .unwrap_or(Route::Other);
+    let status = response.status();
+    metrics::record_request(route, status.as_u16());
+
+    span.record("route", route.as_str());
+    span.record("status", status.as_u16());
+
+    let headers = response.headers_mut();
+    if let Ok(value) = header::HeaderValue::from_str(&request_id) {
+        headers.insert(header::HeaderName::from_static("x-request-id"), value);
+    }
+    if let Some(value) = timing::header(&req).and_then(|timing| timing.parse().ok()) {
+        headers.insert(header::HeaderName::from_static("server-timing"), value);
+    }
+
+    let response = streams::register(response, &req, client_ip.0, &request_id);
+    logging::track(metrics::track(response, route), span, started)
+}
+
+async fn handle(req: HttpRequest, client_ip: ClientIp) -> Result<HttpResponse, ProxyError> {
+    let ClientIp(client_ip) = client_ip;
+
+    if !access::is_allowed(client_ip) {
+        return Err(ProxyError::Forbidden("Forbidden"));
+    }
 
-    // Use total_size when range was truncated by YouTube (start > end)
-    // Otherwise calculate normally
-    let actual_length = if range_request.start > range_request.end {
-        range_request.total_size
+    if !cors::is_request_allowed(&req) {
+        return Err(ProxyError::Forbidden("Origin not allowed"));
+    }
+
+    // preflight requests carry no credentials
+    let auth = if req.method() == actix_web::http::Method::OPTIONS {
+        Auth::Disabled
     } else {
-        range_request.end - range_request.start + 1
+        match api_keys::authenticate(&req) {
+            Ok(auth) => auth,
+            Err(api_keys::Rejection::QuotaExceeded) => return Err(ProxyError::QuotaExceeded),
+            Err(rejection) => return Err(ProxyError::Forbidden(rejection.message())),
+        }
     };
 
-    // Set proper partial content response
-    response.status(actix_web::http::StatusCode::PARTIAL_CONTENT);
-    // Set required headers for partial content responses
-    response.insert_header(("Content-Range", content_range_value));
-    response.insert_header(("Content-Length", actual_length.to_string()));
+    match auth {
+        Auth::Valid {
+            query_key,
+            usage,
+            skip_qhash,
+        } => {
+            let response = proxy(req, client_ip, query_key, skip_qhash).await?;
+            Ok(utils::count_body(response, move |len| {
+                usage.add_bytes(len as u64)
+            }))
+        }
+        Auth::Disabled | Auth::Anonymous => proxy(req, client_ip, None, false).await,
+    }
 }
 
-async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
+#[cfg_attr(not(feature = "qhash"), allow(unused_variables))]
+async fn proxy(
+    req: HttpRequest,
+    client_ip: Option<IpAddr>,
+    api_key: Option<String>,
+    skip_qhash: bool,
+) -> Result<HttpResponse, ProxyError> {
     if req.method() == actix_web::http::Method::OPTIONS {
         let mut response = HttpResponse::Ok();
-        add_headers(&mut response);
+        cors::add_headers(&mut response, &req);
         return Ok(response.finish());
     } else if req.method() != actix_web::http::Method::GET
         && req.method() != actix_web::http::Method::HEAD
     {
         let mut response = HttpResponse::MethodNotAllowed();
-        add_headers(&mut response);
+        cors::add_headers(&mut response, &req);
         return Ok(response.finish());
     }
 
-    // parse query string
-    let mut query = QString::from(req.query_string());
-
-    #[cfg(feature = "qhash")]
+    // opaque tokens carry the sealed path and query, which needs no further signature
+    #[cfg(feature = "tokens")]
+    #[cfg_attr(not(feature = "qhash"), allow(unused_variables))]
+    let (path, query_string, is_token) = match info_span!("token_open")
+        .in_scope(|| token::open(req.path(), req.query_string()))
+        .map_err(ProxyError::InvalidToken)?
     {
-        use std::collections::BTreeSet;
-
-        let secret = env::var("HASH_SECRET");
-        if let Ok(secret) = secret {
-            let Some(qhash) = query.get("qhash") else {
-                return Err("No qhash provided".into());
-            };
-
-            if qhash.len() != 8 {
-                return Err("Invalid qhash provided".into());
-            }
-
-            let path = req.path().as_bytes().to_owned();
-
-            // Store sorted key-value pairs
-            let mut set = BTreeSet::new();
-            {
-                let pairs = query.to_pairs();
-                for (key, value) in &pairs {
-                    if matches!(*key, "qhash" | "range" | "rewrite") {
-                        continue;
-                    }
-                    set.insert((key.as_bytes().to_owned(), value.as_bytes().to_owned()));
-                }
-            }
-
-            let hash = spawn_blocking(move || {
-                let mut hasher = blake3::Hasher::new();
-
-                for (key, value) in set {
-                    hasher.update(&key);
-                    hasher.update(&value);
-                }
-
-                let range_marker = b"/range/";
-
-                // Find the slice before "/range/"
-                if let Some(position) = path
-                    .windows(range_marker.len())
-                    .position(|window| window == range_marker)
-                {
-                    // Update the hasher with the part of the path before "/range/"
-                    // We add +1 to include the "/" in the hash
-                    // This is done for DASH streams for the manifests provided by YouTube
-                    hasher.update(&path[..(position + 1)]);
-                } else {
-                    hasher.update(&path);
-                }
+        Some((path, query)) => (path, query, true),
+        None if token::is_required() => return Err(ProxyError::InvalidToken("No token provided")),
+        None => (
+            req.path().to_string(),
+            req.query_string().to_string(),
+            false,
+        ),
+    };
+    #[cfg(not(feature = "tokens"))]
+    #[cfg_attr(not(feature = "qhash"), allow(unused_variables))]
+    let (path, query_string, is_token) = (
+        req.path().to_string(),
+        req.query_string().to_string(),
+        false,
+    );
 
-                hasher.update(secret.as_bytes());
+    // parse query string
+    let mut query = QString::from(query_string.as_str());
 
-                let hash = hasher.finalize().to_hex();
+    #[cfg(feature = "qhash")]
+    if qhash::is_enabled() && !is_token && !skip_qhash {
+        let path = path.clone();
+        let pairs = query
+            .to_pairs()
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
 
-                hash[..8].to_owned()
-            })
+        let verify_started = Instant::now();
+        let key_version = spawn_blocking(move || qhash::verify(&pairs, &path, client_ip))
+            .instrument(info_span!("qhash_verify"))
             .await
-            .unwrap();
-
-            if hash != qhash {
-                return Err("Invalid qhash provided".into());
-            }
-        }
+            .map_err(|_| ProxyError::Internal("qhash verification failed"))?
+            .map_err(|reason| {
+                metrics::record_qhash_failure(reason);
+                ProxyError::InvalidSignature(reason)
+            })?;
+        metrics::record_qhash_validation(key_version);
+        tracing::Span::current().record("key_version", key_version);
+        timing::record(&req, Phase::Qhash, verify_started.elapsed());
     }
 
-    let Some(host) = query.get("host").map(|s| s.to_string()) else {
-        return Err("No host provided".into());
+    let Some(mut host) = query.get("host").map(|s| s.to_string()) else {
+        return Err(ProxyError::MissingHost);
     };
 
     #[cfg(any(feature = "webp", feature = "avif"))]
@@ -367,29 +624,59 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
     #[cfg(feature = "avif")]
     let avif = query.get("avif") == Some("true");
 
-    let Some(domain) = RE_DOMAIN
-        .captures(host.as_str())
-        .map(|domain| domain.get(1).unwrap().as_str())
-    else {
-        return Err("Invalid host provided".into());
-    };
+    if !RE_DOMAIN.is_match(host.as_str()) {
+        return Err(ProxyError::InvalidHost);
+    }
 
-    if !ALLOWED_DOMAINS.contains(&domain) {
-        return Err("Domain not allowed".into());
+    if !is_domain_allowed(host.as_str()) {
+        return Err(ProxyError::DomainNotAllowed);
     }
 
-    let video_playback = req.path().eq("/videoplayback");
+    let route = Route::classify(&path, &host, query.has("ump"));
+    req.extensions_mut().insert(route);
+
+    req.extensions_mut()
+        .insert(streams::Target::new(&path, &host, &query));
+
+    let span = tracing::Span::current();
+    span.record("host", host.as_str());
+    if let Some(itag) = query.get("itag") {
+        span.record("itag", itag);
+    }
+
+    let stream_guard = match LimitClass::of(route) {
+        Some(class) => match ratelimit::acquire(client_ip, class) {
+            Ok(guard) => guard,
+            Err(rejection) => {
+                return Err(ProxyError::RateLimited {
+                    retry_after: rejection.retry_after(),
+                })
+            }
+        },
+        None => None,
+    };
+
+    let video_playback = path.eq("/videoplayback");
 
     if video_playback {
         if let Some(expiry) = query.get("expire") {
-            let expiry = expiry.parse::<i64>()?;
-            let now = SystemTime::now();
-            let now = now
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_secs() as i64;
+            let expiry = expiry
+                .parse::<i64>()
+                .map_err(|_| ProxyError::InvalidParameter("expire"))?;
+            let now = utils::now() as i64;
             if now > expiry {
-                return Err("Expire time in past".into());
+                // continue with a fresh URL from the backend instead
+                let Some(fresh) = refresh::refresh(&query).await else {
+                    return Err(ProxyError::Expired);
+                };
+                host = fresh.host_str().unwrap_or_default().to_string();
+                query = QString::new(
+                    fresh
+                        .query_pairs()
+                        .into_owned()
+                        .chain([("host".to_string(), host.clone())])
+                        .collect(),
+                );
             }
         }
     }
@@ -403,57 +690,103 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
 
     let clen = query
         .get("clen")
-        .map(|s| s.to_string().parse::<u64>().unwrap());
-
-    if video_playback && !query.has("range") {
-        if let Some(range) = req.headers().get("range") {
-            let range = range.to_str().unwrap();
-            let range = range.replace("bytes=", "");
-            let range = range.split('-').collect::<Vec<_>>();
-            let start = range[0].parse::<u64>().unwrap();
-            let end = match range[1].parse::<u64>() {
-                Ok(end) => end,
-                Err(_) => {
-                    if let Some(clen) = clen {
-                        clen - 1
-                    } else {
-                        0
-                    }
+        .map(|s| s.parse::<u64>())
+        .transpose()
+        .map_err(|_| ProxyError::InvalidParameter("clen"))?;
+
+    // whether the client asked for part of the content
+    let mut partial = query.has("range");
+
+    if video_playback && !partial {
+        // `lmt` is the last modification time in microseconds
+        let last_modified = query
+            .get("lmt")
+            .and_then(|lmt| lmt.parse::<u64>().ok())
+            .map(|lmt| UNIX_EPOCH + Duration::from_micros(lmt));
+
+        let specs = req
+            .headers()
+            .get("range")
+            .and_then(|range| range.to_str().ok())
+            .and_then(range::parse_header)
+            .filter(|_| range::if_range_matches(&req, last_modified));
+
+        match (specs, clen) {
+            (Some(specs), Some(clen)) => match range::resolve(&specs, clen) {
+                Some(range) => {
+                    query.add_pair(("range", range.to_query()));
+                    partial = true;
                 }
-            };
-            if end != 0 {
-                let range = format!("{}-{}", start, end);
+                None => return Err(ProxyError::RangeNotSatisfiable(clen)),
+            },
+            // without the size, only ranges with both ends can be requested
+            (Some(specs), None) => {
+                if let Some(RangeSpec::Bounded(start, end)) = range::coalesce(&specs) {
+                    query.add_pair(("range", ByteRange { start, end }.to_query()));
+                    partial = true;
+                }
+            }
+            (None, Some(clen)) if clen > 0 => {
+                let range = format!("0-{}", clen - 1);
                 query.add_pair(("range", range));
             }
-        } else if let Some(clen) = clen {
-            let range = format!("0-{}", clen - 1);
-            query.add_pair(("range", range));
+            _ => {}
         }
     }
 
     let range = query.get("range").map(|s| s.to_string());
 
+    // players probe the size with HEAD, which the URL already tells us
+    let synthesize_head =
+        video_playback && req.method() == actix_web::http::Method::HEAD && clen.is_some();
+
+    if synthesize_head && !*VERIFY_HEAD {
+        return Ok(head_response(
+            &req,
+            clen.unwrap(),
+            mime_type.as_deref(),
+            query.get("dur"),
+            range.as_deref().filter(|_| partial),
+        ));
+    }
+
+    let duration = query.get("dur").map(|s| s.to_string());
+
     let qs = {
-        let collected = query
+        let mut collected = query
             .into_pairs()
             .into_iter()
-            .filter(|(key, _)| !matches!(key.as_str(), "host" | "rewrite" | "qhash"))
+            .filter(|(key, _)| {
+                !matches!(
+                    key.as_str(),
+                    "host" | "rewrite" | "qhash" | "qh2" | api_keys::QUERY_PARAM
+                )
+            })
+            .filter(|(key, _)| !synthesize_head || key != "range")
             .collect::<Vec<_>>();
+        // checking that the URL works only takes its first byte
+        if synthesize_head {
+            collected.push(("range".to_string(), "0-0".to_string()));
+        }
         QString::new(collected)
     };
 
-    let mut url = Url::parse(&format!("https://{}{}", host, req.path()))?;
+    let mut url = Url::parse(&format!("https://{}{}", host, path))
+        .map_err(|_| ProxyError::InvalidParameter("path"))?;
     url.set_query(Some(qs.to_string().as_str()));
 
     let method = {
         if is_web && video_playback {
             Method::POST
+        } else if synthesize_head {
+            Method::GET
         } else {
-            Method::from_str(req.method().as_str())?
+            Method::from_str(req.method().as_str())
+                .map_err(|_| ProxyError::Internal("unsupported method"))?
         }
     };
 
-    let mut request = Request::new(method, url);
+    let mut request = Request::new(method, url.clone());
 
     if is_web && video_playback {
         request.body_mut().replace(Body::from("x\0"));
@@ -464,32 +797,164 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
     for (key, value) in req.headers() {
         let key = key.as_str();
         if is_header_allowed(key) {
+            let (Ok(key), Ok(value)) = (
+                HeaderName::from_str(key),
+                HeaderValue::from_bytes(value.as_bytes()),
+            ) else {
+                return Err(ProxyError::InvalidParameter("header"));
+            };
+            request_headers.insert(key, value);
+        }
+    }
+
+    if is_android {
+        request_headers.insert("User-Agent", HeaderValue::from_static(ANDROID_USER_AGENT));
+    }
+
+    // everything else takes the range as a header
+    if !video_playback {
+        if let Some(range) = range::upstream_header(&req) {
             request_headers.insert(
-                HeaderName::from_str(key)?,
-                HeaderValue::from_bytes(value.as_bytes())?,
+                "Range",
+                range
+                    .parse()
+                    .map_err(|_| ProxyError::Internal("invalid range header"))?,
             );
         }
     }
 
-    if is_android {
-        request_headers.insert("User-Agent", ANDROID_USER_AGENT.parse()?);
+    let prefetch_request =
+        if prefetch::is_enabled() && video_playback && req.method() == actix_web::http::Method::GET
+        {
+            request.try_clone()
+        } else {
+            None
+        };
+
+    let retry_request = if video_playback && refresh::is_enabled() {
+        request.try_clone()
+    } else {
+        None
+    };
+
+    // lasts until the response headers are in, i.e. the time to first byte
+    let upstream_span = info_span!("upstream", egress = Empty, status = Empty);
+    let started = Instant::now();
+
+    let (resp, egress) = async {
+        // let the node owning this URL fetch it once for the whole cluster, but
+        // not partial requests, which the owner would neither forward nor tell apart
+        let cluster_resp = if cluster::is_enabled()
+            && req.method() == actix_web::http::Method::GET
+            && !request.headers().contains_key(reqwest::header::RANGE)
+            && cluster::is_bufferable(route, range.as_deref())
+        {
+            cluster::fetch_from_owner(&request).await
+        } else {
+            None
+        };
+
+        match (cluster_resp, prefetch_request.is_some()) {
+            (Some(resp), _) => (Ok(resp), Egress::Cluster),
+            (None, true) => match prefetch::claim(request.url()).await {
+                Some(resp) => (Ok(resp), Egress::Prefetch),
+                None => (CLIENT.execute(request).await, Egress::Direct),
+            },
+            (None, false) => (CLIENT.execute(request).await, Egress::Direct),
+        }
+    }
+    .instrument(upstream_span.clone())
+    .await;
+    metrics::record_upstream(&host, egress, started.elapsed(), &resp);
+    timing::record(&req, Phase::Upstream, started.elapsed());
+    upstream_span.record("egress", egress.as_str());
+    if let Ok(resp) = &resp {
+        upstream_span.record("status", resp.status().as_u16());
+    }
+
+    let resp = resp?;
+
+    // URLs get revoked before they expire, retry once with a fresh one
+    let resp = match retry_request {
+        Some(mut retry_request) if resp.status() == reqwest::StatusCode::FORBIDDEN => {
+            match refresh::refresh(&QString::from(url.query().unwrap_or_default())).await {
+                Some(fresh) => {
+                    *retry_request.url_mut() = fresh;
+                    let started = Instant::now();
+                    let resp = CLIENT
+                        .execute(retry_request)
+                        .instrument(info_span!("upstream_retry"))
+                        .await;
+                    metrics::record_upstream(&host, Egress::Direct, started.elapsed(), &resp);
+                    timing::record(&req, Phase::Upstream, started.elapsed());
+                    resp?
+                }
+                None => resp,
+            }
+        }
+        _ => resp,
+    };
+
+    // read ahead the next range of the same size
+    if let (Some(prefetch_request), Some(range)) = (prefetch_request, range.as_ref()) {
+        if resp.status().is_success() {
+            let client_id = client_ip.map(|ip| ip.to_string()).unwrap_or_default();
+            prefetch::schedule(&CLIENT, &prefetch_request, range, clen, &client_id);
+        }
+    }
+
+    if synthesize_head && resp.status().is_success() {
+        return Ok(head_response(
+            &req,
+            clen.unwrap(),
+            mime_type.as_deref(),
+            duration.as_deref(),
+            range.as_deref().filter(|_| partial),
+        ));
     }
 
-    let resp = CLIENT.execute(request).await?;
+    let status = StatusCode::from_u16(resp.status().as_u16())
+        .map_err(|_| ProxyError::Internal("invalid upstream status"))?;
+    let mut response = HttpResponse::build(status);
 
-    let mut response = HttpResponse::build(StatusCode::from_u16(resp.status().as_u16())?);
+    cors::add_headers(&mut response, &req);
 
-    add_headers(&mut response);
+    let rewrite_location = *REWRITE_REDIRECTS && resp.status().is_redirection();
 
     for (key, value) in resp.headers() {
+        if rewrite_location && key == LOCATION {
+            continue;
+        }
         if is_header_allowed(key.as_str()) {
             response.append_header((key.as_str(), value.as_bytes()));
         }
     }
 
+    if rewrite_location {
+        let location = resp
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| url.join(location).ok());
+
+        match location {
+            Some(location) if is_redirect_allowed(&location) => {
+                response.append_header((
+                    "location",
+                    utils::localize_url(location.as_str(), &host, client_ip, api_key.as_deref()),
+                ));
+            }
+            Some(location) => warn!(
+                "Dropping redirect to {}",
+                location.host_str().unwrap_or_default()
+            ),
+            None => {}
+        }
+    }
+
     // Fix range request handling - convert 200 to 206 if we have a range request
     // and ensure Content-Range header is present
-    handle_range_response_correction(&mut response, range.as_ref(), clen, &resp);
+    handle_range_response_correction(&mut response, range.as_ref(), partial, clen, &resp);
 
     if rewrite {
         if let Some(content_type) = resp.headers().get("content-type") {
@@ -497,12 +962,19 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
             if !disallow_image_transcoding
                 && (content_type == "image/webp" || content_type == "image/jpeg" && avif)
             {
-                let resp_bytes = resp.bytes().await.unwrap();
-                let (body, content_type) = spawn_blocking(|| {
+                let resp_bytes = resp.bytes().await?;
+                let slot = health::TranscodeSlot::acquire();
+                let transform_started = Instant::now();
+                let (body, content_type) = spawn_blocking(move || {
+                    let _slot = slot;
                     use ravif::{Encoder, Img};
+
+                    let started = Instant::now();
                     use rgb::FromSlice;
 
-                    let image = image::load_from_memory(&resp_bytes).unwrap();
+                    let Ok(image) = image::load_from_memory(&resp_bytes) else {
+                        return (resp_bytes.into(), None);
+                    };
 
                     let width = image.width() as usize;
                     let height = image.height() as usize;
@@ -518,30 +990,49 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
                         .encode_rgb(buffer);
 
                     if let Ok(res) = res {
-                        (res.avif_file.to_vec(), "image/avif")
+                        metrics::record_transcode(
+                            "avif",
+                            started.elapsed(),
+                            resp_bytes.len(),
+                            res.avif_file.len(),
+                        );
+                        (res.avif_file.to_vec(), Some("image/avif"))
                     } else {
-                        (resp_bytes.into(), "image/jpeg")
+                        (resp_bytes.into(), None)
                     }
                 })
+                .instrument(info_span!("transcode", format = "avif"))
                 .await
-                .unwrap();
-                response.content_type(content_type);
+                .map_err(|_| ProxyError::Internal("image transcoding failed"))?;
+                timing::record(&req, Phase::Transform, transform_started.elapsed());
+                // images that couldn't be transcoded keep the upstream content type
+                if let Some(content_type) = content_type {
+                    response.content_type(content_type);
+                }
                 return Ok(response.body(body));
             }
 
             #[cfg(feature = "webp")]
             if !disallow_image_transcoding && content_type == "image/jpeg" {
-                let resp_bytes = resp.bytes().await.unwrap();
-                let (body, content_type) = spawn_blocking(|| {
+                let resp_bytes = resp.bytes().await?;
+                let slot = health::TranscodeSlot::acquire();
+                let transform_started = Instant::now();
+                let (body, content_type) = spawn_blocking(move || {
+                    let _slot = slot;
                     use libwebp_sys::{WebPEncodeRGB, WebPFree};
 
-                    let image = image::load_from_memory(&resp_bytes).unwrap();
+                    let started = Instant::now();
+
+                    let Ok(image) = image::load_from_memory(&resp_bytes) else {
+                        return (resp_bytes.into(), None);
+                    };
                     let width = image.width();
                     let height = image.height();
 
                     let quality = 85;
 
-                    let data = image.as_rgb8().unwrap().as_raw();
+                    let image = image.into_rgb8();
+                    let data = image.as_raw();
 
                     let bytes: Vec<u8> = unsafe {
                         let mut out_buf = std::ptr::null_mut();
@@ -559,23 +1050,36 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
                         vec
                     };
 
+                    metrics::record_transcode(
+                        "webp",
+                        started.elapsed(),
+                        resp_bytes.len(),
+                        bytes.len(),
+                    );
+
                     if bytes.len() < resp_bytes.len() {
-                        (bytes, "image/webp")
+                        (bytes, Some("image/webp"))
                     } else {
-                        (resp_bytes.into(), "image/jpeg")
+                        (resp_bytes.into(), None)
                     }
                 })
+                .instrument(info_span!("transcode", format = "webp"))
                 .await
-                .unwrap();
-                response.content_type(content_type);
+                .map_err(|_| ProxyError::Internal("image transcoding failed"))?;
+                timing::record(&req, Phase::Transform, transform_started.elapsed());
+                if let Some(content_type) = content_type {
+                    response.content_type(content_type);
+                }
                 return Ok(response.body(body));
             }
 
             if content_type == "application/x-mpegurl"
                 || content_type == "application/vnd.apple.mpegurl"
             {
-                let resp_str = resp.text().await.unwrap();
+                let resp_str = resp.text().await?;
 
+                let _span = info_span!("manifest_rewrite", format = "hls").entered();
+                let transform_started = Instant::now();
                 let modified = resp_str
                     .lines()
                     .map(|line| {
@@ -585,80 +1089,94 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
                             if url.starts_with("https://") {
                                 return line.replace(
                                     url,
-                                    utils::localize_url(url, host.as_str()).as_str(),
+                                    utils::localize_url(
+                                        url,
+                                        host.as_str(),
+                                        client_ip,
+                                        api_key.as_deref(),
+                                    )
+                                    .as_str(),
                                 );
                             }
                         }
-                        utils::localize_url(line, host.as_str())
+                        utils::localize_url(line, host.as_str(), client_ip, api_key.as_deref())
                     })
                     .collect::<Vec<String>>()
                     .join("\n");
 
+                timing::record(&req, Phase::Transform, transform_started.elapsed());
                 return Ok(response.body(modified));
             }
             if content_type == "video/vnd.mpeg.dash.mpd" || content_type == "application/dash+xml" {
-                let resp_str = resp.text().await.unwrap();
+                let resp_str = resp.text().await?;
+
+                let _span = info_span!("manifest_rewrite", format = "dash").entered();
+                let transform_started = Instant::now();
                 let mut new_resp = resp_str.clone();
                 let captures = RE_DASH_MANIFEST.captures_iter(&resp_str);
                 for capture in captures {
                     let url = capture.get(1).unwrap().as_str();
-                    let new_url = utils::localize_url(url, host.as_str());
+                    let new_url =
+                        utils::localize_url(url, host.as_str(), client_ip, api_key.as_deref());
                     let new_url = utils::escape_xml(new_url.as_str());
                     new_resp = new_resp.replace(url, new_url.as_ref());
                 }
+                timing::record(&req, Phase::Transform, transform_started.elapsed());
                 return Ok(response.body(new_resp));
             }
         }
     }
 
-    if let Some(content_length) = resp.headers().get("content-length") {
-        response.no_chunking(content_length.to_str().unwrap().parse::<u64>().unwrap());
+    let content_length = resp
+        .headers()
+        .get("content-length")
+        .and_then(|content_length| content_length.to_str().ok())
+        .and_then(|content_length| content_length.parse::<u64>().ok());
+    if let Some(content_length) = content_length {
+        response.no_chunking(content_length);
     }
 
     if is_ump && resp.status().is_success() {
         if let Some(mime_type) = mime_type {
             response.content_type(mime_type);
         }
-        if req.headers().contains_key("range") {
+        if let (true, Some(clen)) = (partial, clen) {
+            let range = range
+                .as_deref()
+                .and_then(|range| ByteRange::from_query(range, clen));
             // check if it's not the whole stream
-            if let Some(ref range) = range {
-                if let Some(clen) = clen {
-                    if range != &format!("0-{}", clen - 1) {
-                        response.status(StatusCode::PARTIAL_CONTENT);
-
-                        // Add proper Content-Range header for UMP streams
-                        let content_range_value = format!("bytes {}/{}", range, clen);
-                        response.insert_header(("Content-Range", content_range_value.clone()));
-                    }
-                }
+            if let Some(range) = range.filter(|range| range.len() != clen) {
+                response.status(StatusCode::PARTIAL_CONTENT);
+
+                // Add proper Content-Range header for UMP streams
+                response.insert_header(("Content-Range", range.content_range(clen)));
             }
         }
         let resp = resp.bytes_stream();
-        let resp = resp.map_err(|e| io::Error::new(ErrorKind::Other, e));
+        let resp = resp.map_err(io::Error::other);
         let transformed_stream = UmpTransformStream::new(resp);
-        // print errors
-        let transformed_stream = transformed_stream.map_err(|e| {
-            eprintln!("UMP Transforming Error: {}", e);
+        // lasts as long as the stream, and holds its errors
+        let ump_span = info_span!("ump_transform");
+        let transformed_stream = transformed_stream.map_err(move |e| {
+            ump_span.in_scope(|| warn!("UMP Transforming Error: {}", e));
             e
         });
 
         // calculate content length from clen and range
         if let Some(clen) = clen {
-            let length = if let Some(ref range) = range {
-                let range = range.replace("bytes=", "");
-                let range = range.split('-').collect::<Vec<_>>();
-                let start = range[0].parse::<u64>().unwrap();
-                let end = range[1].parse::<u64>().unwrap_or(clen - 1);
-                end - start + 1
-            } else {
-                clen
-            };
+            let length = range
+                .as_deref()
+                .and_then(|range| ByteRange::from_query(range, clen))
+                .map_or(clen, |range| range.len());
             response.no_chunking(length);
         }
 
-        return Ok(response.streaming(transformed_stream));
+        let transformed_stream = ThrottledStream::new(transformed_stream);
+
+        return Ok(response.streaming(ratelimit::guard_stream(transformed_stream, stream_guard)));
     }
 
     // Stream response
-    Ok(response.streaming(resp.bytes_stream()))
+    let resp = ThrottledStream::new(resp.bytes_stream());
+    Ok(response.streaming(ratelimit::guard_stream(resp, stream_guard)))
 }
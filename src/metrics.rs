@@ -0,0 +1,377 @@
+use crate::utils;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::{HttpResponse, Responder};
+use once_cell::sync::Lazy;
+use prometheus_client::collector::Collector;
+use prometheus_client::encoding::{DescriptorEncoder, EncodeLabelSet, EncodeMetric};
+use prometheus_client::metrics::counter::{ConstCounter, Counter};
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use std::time::Duration;
+
+/// The kind of content a request is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Route {
+    Videoplayback,
+    Ump,
+    Image,
+    Hls,
+    Dash,
+    Other,
+}
+
+impl Route {
+    pub fn classify(path: &str, host: &str, ump: bool) -> Route {
+        if path == "/videoplayback" {
+            if ump {
+                Route::Ump
+            } else {
+                Route::Videoplayback
+            }
+        } else if path.starts_with("/api/manifest/hls") || path.ends_with(".m3u8") {
+            Route::Hls
+        } else if path.starts_with("/api/manifest/dash") || path.ends_with(".mpd") {
+            Route::Dash
+        } else if host.ends_with("ytimg.com")
+            || host.ends_with("ggpht.com")
+            || host.ends_with("googleusercontent.com")
+        {
+            Route::Image
+        } else {
+            Route::Other
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Route::Videoplayback => "videoplayback",
+            Route::Ump => "ump",
+            Route::Image => "image",
+            Route::Hls => "hls",
+            Route::Dash => "dash",
+            Route::Other => "other",
+        }
+    }
+}
+
+/// The way a response was fetched from upstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Egress {
+    /// Our own connection, through `PROXY` if one is set.
+    Direct,
+    /// The cluster node owning the URL.
+    Cluster,
+    /// A range read ahead earlier.
+    Prefetch,
+}
+
+impl Egress {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Egress::Direct if *USES_PROXY => "proxy",
+            Egress::Direct => "direct",
+            Egress::Cluster => "cluster",
+            Egress::Prefetch => "prefetch",
+        }
+    }
+}
+
+static USES_PROXY: Lazy<bool> = Lazy::new(|| std::env::var("PROXY").is_ok());
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    route: &'static str,
+    status: u16,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RouteLabels {
+    route: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DomainLabels {
+    domain: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct UpstreamLabels {
+    egress: &'static str,
+    outcome: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct FormatLabels {
+    format: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ReasonLabels {
+    reason: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct KeyVersionLabels {
+    version: usize,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ApiKeyLabels {
+    label: String,
+}
+
+type HistogramFamily<L> = Family<L, Histogram, fn() -> Histogram>;
+
+struct Metrics {
+    requests: Family<RequestLabels, Counter>,
+    bytes: Family<RouteLabels, Counter>,
+    active_streams: Gauge,
+    upstream_latency: HistogramFamily<DomainLabels>,
+    upstream_requests: Family<UpstreamLabels, Counter>,
+    transcode_duration: HistogramFamily<FormatLabels>,
+    transcode_saved_bytes: Family<FormatLabels, Counter>,
+    qhash_failures: Family<ReasonLabels, Counter>,
+    qhash_validations: Family<KeyVersionLabels, Counter>,
+    api_key_requests: Family<ApiKeyLabels, Counter>,
+    api_key_bytes: Family<ApiKeyLabels, Counter>,
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(|| Metrics {
+    requests: Family::default(),
+    bytes: Family::default(),
+    active_streams: Gauge::default(),
+    upstream_latency: Family::new_with_constructor(|| {
+        Histogram::new(exponential_buckets(0.01, 2.0, 12))
+    }),
+    upstream_requests: Family::default(),
+    transcode_duration: Family::new_with_constructor(|| {
+        Histogram::new(exponential_buckets(0.005, 2.0, 12))
+    }),
+    transcode_saved_bytes: Family::default(),
+    qhash_failures: Family::default(),
+    qhash_validations: Family::default(),
+    api_key_requests: Family::default(),
+    api_key_bytes: Family::default(),
+});
+
+/// Values other modules already keep count of, read on every scrape.
+#[derive(Debug)]
+struct Counts;
+
+impl Collector for Counts {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        let blocked = ConstCounter::new(crate::access::blocked_count());
+        let metric_encoder = encoder.encode_descriptor(
+            "blocked_requests",
+            "Requests refused by the IP allow and deny lists",
+            None,
+            blocked.metric_type(),
+        )?;
+        blocked.encode(metric_encoder)
+    }
+}
+
+static REGISTRY: Lazy<Registry> = Lazy::new(|| {
+    let mut registry = Registry::with_prefix("piped_proxy");
+    let metrics = &*METRICS;
+
+    registry.register(
+        "requests",
+        "Requests by route and status",
+        metrics.requests.clone(),
+    );
+    registry.register(
+        "streamed_bytes",
+        "Bytes sent to clients by route",
+        metrics.bytes.clone(),
+    );
+    registry.register(
+        "active_streams",
+        "Responses currently being streamed",
+        metrics.active_streams.clone(),
+    );
+    registry.register(
+        "upstream_latency_seconds",
+        "Time until upstream responded, by domain",
+        metrics.upstream_latency.clone(),
+    );
+    registry.register(
+        "upstream_requests",
+        "Upstream requests by egress and outcome",
+        metrics.upstream_requests.clone(),
+    );
+    registry.register(
+        "transcode_duration_seconds",
+        "Time spent transcoding images",
+        metrics.transcode_duration.clone(),
+    );
+    registry.register(
+        "transcode_saved_bytes",
+        "Bytes saved by transcoding images",
+        metrics.transcode_saved_bytes.clone(),
+    );
+    registry.register(
+        "qhash_failures",
+        "Requests rejected because of their qhash",
+        metrics.qhash_failures.clone(),
+    );
+    registry.register(
+        "qhash_validations",
+        "Requests accepted by each qhash key version, 0 being the current key",
+        metrics.qhash_validations.clone(),
+    );
+    registry.register(
+        "api_key_requests",
+        "Requests by API key label",
+        metrics.api_key_requests.clone(),
+    );
+    registry.register(
+        "api_key_bytes",
+        "Bytes sent by API key label",
+        metrics.api_key_bytes.clone(),
+    );
+    registry.register_collector(Box::new(Counts));
+
+    registry
+});
+
+pub fn record_request(route: Route, status: u16) {
+    METRICS
+        .requests
+        .get_or_create(&RequestLabels {
+            route: route.as_str(),
+            status,
+        })
+        .inc();
+}
+
+/// Records an upstream request, labelled with the registered domain of the host
+/// to keep the number of series down.
+pub fn record_upstream<T>(
+    host: &str,
+    egress: Egress,
+    elapsed: Duration,
+    result: &Result<reqwest::Response, T>,
+) {
+    let domain = crate::RE_DOMAIN
+        .captures(host)
+        .and_then(|captures| captures.get(1))
+        .map_or(host, |domain| domain.as_str())
+        .to_string();
+
+    let outcome = match result {
+        Ok(resp) if resp.status().is_server_error() => "server_error",
+        Ok(resp) if resp.status().is_client_error() => "client_error",
+        Ok(_) => "ok",
+        Err(_) => "failed",
+    };
+
+    if result.is_ok() {
+        METRICS
+            .upstream_latency
+            .get_or_create(&DomainLabels { domain })
+            .observe(elapsed.as_secs_f64());
+    }
+
+    METRICS
+        .upstream_requests
+        .get_or_create(&UpstreamLabels {
+            egress: egress.as_str(),
+            outcome,
+        })
+        .inc();
+}
+
+#[cfg(any(feature = "webp", feature = "avif"))]
+pub fn record_transcode(format: &'static str, elapsed: Duration, input: usize, output: usize) {
+    let labels = FormatLabels { format };
+    METRICS
+        .transcode_duration
+        .get_or_create(&labels)
+        .observe(elapsed.as_secs_f64());
+    METRICS
+        .transcode_saved_bytes
+        .get_or_create(&labels)
+        .inc_by(input.saturating_sub(output) as u64);
+}
+
+#[cfg(feature = "qhash")]
+pub fn record_qhash_failure(reason: &'static str) {
+    METRICS
+        .qhash_failures
+        .get_or_create(&ReasonLabels { reason })
+        .inc();
+}
+
+#[cfg(feature = "qhash")]
+pub fn record_qhash_validation(version: usize) {
+    METRICS
+        .qhash_validations
+        .get_or_create(&KeyVersionLabels { version })
+        .inc();
+}
+
+pub fn record_api_key_request(label: &str) {
+    METRICS
+        .api_key_requests
+        .get_or_create(&ApiKeyLabels {
+            label: label.to_string(),
+        })
+        .inc();
+}
+
+pub fn record_api_key_bytes(label: &str, bytes: u64) {
+    METRICS
+        .api_key_bytes
+        .get_or_create(&ApiKeyLabels {
+            label: label.to_string(),
+        })
+        .inc_by(bytes);
+}
+
+/// Counts an active stream until dropped.
+struct ActiveStream;
+
+impl ActiveStream {
+    fn new() -> Self {
+        METRICS.active_streams.inc();
+        ActiveStream
+    }
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        METRICS.active_streams.dec();
+    }
+}
+
+/// Counts the bytes of the response body, and the response as an active stream
+/// while a streamed body is being sent.
+pub fn track(response: HttpResponse, route: Route) -> HttpResponse {
+    let active = matches!(response.body().size(), BodySize::Stream).then(ActiveStream::new);
+    let bytes = METRICS
+        .bytes
+        .get_or_create(&RouteLabels {
+            route: route.as_str(),
+        })
+        .clone();
+
+    utils::count_body(response, move |len| {
+        let _active = &active;
+        bytes.inc_by(len as u64);
+    })
+}
+
+pub async fn handler() -> impl Responder {
+    let mut body = String::new();
+    if let Err(e) = prometheus_client::encoding::text::encode(&mut body, &REGISTRY) {
+        tracing::error!("Could not encode metrics: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type("application/openmetrics-text; version=1.0.0; charset=utf-8")
+        .body(body)
+}
@@ -0,0 +1,164 @@
+use actix_web::http::header::HeaderMap;
+use once_cell::sync::OnceCell;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use reqwest::header::{HeaderName, HeaderValue};
+use std::env;
+use tracing::Span;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+static PROVIDER: OnceCell<SdkTracerProvider> = OnceCell::new();
+
+/// Exports spans over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set. The other `OTEL_*` variables of
+/// the specification, such as `OTEL_SERVICE_NAME`, are honoured as well.
+pub fn layer<S>() -> Option<OpenTelemetryLayer<S, SdkTracer>>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    if env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_err()
+        && env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_err()
+    {
+        return None;
+    }
+
+    let exporter = match SpanExporter::builder().with_http().build() {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            // the subscriber isn't up yet
+            eprintln!("Could not set up OTLP export: {}", e);
+            return None;
+        }
+    };
+
+    layer_with(exporter)
+}
+
+fn layer_with<S>(exporter: SpanExporter) -> Option<OpenTelemetryLayer<S, SdkTracer>>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    let mut resource = Resource::builder();
+    if env::var("OTEL_SERVICE_NAME").is_err() {
+        resource = resource.with_service_name(env!("CARGO_PKG_NAME"));
+    }
+    let resource = resource
+        .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+        .build();
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    PROVIDER.set(provider).ok()?;
+
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Sends the spans still waiting in the batch. Blocks until they are out.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            tracing::warn!("Could not flush spans: {}", e);
+        }
+    }
+}
+
+struct RequestHeaders<'a>(&'a HeaderMap);
+
+impl Extractor for RequestHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+struct UpstreamHeaders<'a>(&'a mut reqwest::header::HeaderMap);
+
+impl Injector for UpstreamHeaders<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Continues the trace of the `traceparent` header of the request, if any.
+pub fn set_parent(span: &Span, headers: &HeaderMap) {
+    let context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&RequestHeaders(headers)));
+    // fails only without the layer, when there is nothing to continue
+    let _ = span.set_parent(context);
+}
+
+/// Adds the `traceparent` of the current span to a request of our own.
+pub fn inject(headers: &mut reqwest::header::HeaderMap) {
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut UpstreamHeaders(headers))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use opentelemetry_otlp::WithExportConfig;
+    use std::sync::mpsc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn spans_are_exported_and_propagated() {
+        let (sender, exports) = mpsc::channel();
+        let collector = test_server::serve(move |request| {
+            let _ = sender.send((request.target, request.body));
+            Vec::new()
+        });
+
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", collector))
+            .build()
+            .unwrap();
+        let layer = layer_with(exporter).expect("export not set up");
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("collector_test_span");
+            let _entered = span.enter();
+            inject(&mut headers);
+        });
+        shutdown();
+
+        // traceparent: version-trace id-span id-flags
+        let traceparent = headers["traceparent"].to_str().unwrap();
+        let parts = traceparent.split('-').collect::<Vec<_>>();
+        assert_eq!(parts.len(), 4);
+        assert_ne!(parts[1], "0".repeat(32));
+
+        let (path, body) = exports
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .expect("nothing exported");
+        assert_eq!(path, "/v1/traces");
+        let span_name = b"collector_test_span";
+        assert!(body
+            .windows(span_name.len())
+            .any(|window| window == span_name));
+    }
+}
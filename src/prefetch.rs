@@ -0,0 +1,303 @@
+use crate::utils;
+use bytes::Bytes;
+use futures_util::future::{BoxFuture, Shared};
+use futures_util::FutureExt;
+use once_cell::sync::Lazy;
+use qstring::QString;
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Request, StatusCode, Url};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A speculatively fetched range, kept around until the player asks for it.
+struct Prefetched {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Prefetched {
+    fn to_response(&self) -> reqwest::Response {
+        let mut response = http::Response::new(self.body.clone());
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        reqwest::Response::from(response)
+    }
+}
+
+type PrefetchFuture = Shared<BoxFuture<'static, Option<Arc<Prefetched>>>>;
+
+struct Entry {
+    id: u64,
+    client: String,
+    future: PrefetchFuture,
+}
+
+struct Limits {
+    enabled: bool,
+    timeout: Duration,
+    max_per_client: usize,
+    max_total: usize,
+    max_size: u64,
+}
+
+static LIMITS: Lazy<Limits> = Lazy::new(|| Limits {
+    enabled: utils::get_env_bool("PREFETCH"),
+    timeout: Duration::from_secs(utils::get_env_number("PREFETCH_TIMEOUT", 10)),
+    max_per_client: utils::get_env_number("PREFETCH_MAX_PER_CLIENT", 2),
+    max_total: utils::get_env_number("PREFETCH_MAX_TOTAL", 64),
+    max_size: utils::get_env_number("PREFETCH_MAX_SIZE", 8 * 1024 * 1024),
+});
+
+static ENTRIES: Lazy<Mutex<HashMap<String, Entry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+pub fn is_enabled() -> bool {
+    LIMITS.enabled
+}
+
+/// Takes a prefetched response for the given upstream URL, waiting for it if the
+/// speculative request is still in flight.
+pub async fn claim(url: &Url) -> Option<reqwest::Response> {
+    if !LIMITS.enabled {
+        return None;
+    }
+
+    take(url).await
+}
+
+async fn take(url: &Url) -> Option<reqwest::Response> {
+    let entry = ENTRIES.lock().unwrap().remove(&buffer_key(url))?;
+
+    let prefetched = entry.future.await?;
+
+    Some(prefetched.to_response())
+}
+
+fn next_range_url(url: &Url, range: &str, clen: Option<u64>) -> Option<Url> {
+    let (start, end) = range.split_once('-')?;
+    let start = start.parse::<u64>().ok()?;
+    let end = end.parse::<u64>().ok()?;
+
+    if end < start {
+        return None;
+    }
+
+    // the range comes from the client, so it may sit right at the end of u64
+    let next_start = end.checked_add(1)?;
+    let mut next_end = next_start.checked_add(end - start)?;
+
+    if let Some(clen) = clen {
+        if next_start >= clen {
+            return None;
+        }
+        next_end = next_end.min(clen - 1);
+    }
+
+    let next_range = format!("{}-{}", next_start, next_end);
+
+    // built like the URLs in `proxy`, which leave commas and such unencoded
+    let pairs = url
+        .query_pairs()
+        .map(|(key, value)| {
+            if key == "range" {
+                (key.into_owned(), next_range.clone())
+            } else {
+                (key.into_owned(), value.into_owned())
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut next = url.clone();
+    next.set_query(Some(&QString::new(pairs).to_string()));
+
+    Some(next)
+}
+
+/// Identifies a URL by its decoded query, so differences in percent-encoding
+/// don't keep a prefetched range from being claimed.
+fn buffer_key(url: &Url) -> String {
+    let mut key = format!("{}{}", url.host_str().unwrap_or_default(), url.path());
+    for (name, value) in url.query_pairs() {
+        key.push('\0');
+        key.push_str(&name);
+        key.push('=');
+        key.push_str(&value);
+    }
+    key
+}
+
+/// Starts fetching the range following the one in `request` into the prefetch
+/// buffer, as long as the per-client and global limits allow it.
+pub fn schedule(
+    client: &'static Client,
+    request: &Request,
+    range: &str,
+    clen: Option<u64>,
+    client_id: &str,
+) {
+    if LIMITS.enabled {
+        schedule_with(&LIMITS, client, request, range, clen, client_id);
+    }
+}
+
+fn schedule_with(
+    limits: &Limits,
+    client: &'static Client,
+    request: &Request,
+    range: &str,
+    clen: Option<u64>,
+    client_id: &str,
+) {
+    let Some(next_url) = next_range_url(request.url(), range, clen) else {
+        return;
+    };
+
+    let Some(mut next_request) = request.try_clone() else {
+        return;
+    };
+    let key = buffer_key(&next_url);
+    *next_request.url_mut() = next_url;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    {
+        let mut entries = ENTRIES.lock().unwrap();
+
+        if entries.contains_key(&key) || entries.len() >= limits.max_total {
+            return;
+        }
+
+        let client_count = entries
+            .values()
+            .filter(|entry| entry.client == client_id)
+            .count();
+        if client_count >= limits.max_per_client {
+            return;
+        }
+
+        let max_size = limits.max_size;
+        let future = async move {
+            let resp = client.execute(next_request).await.ok()?;
+
+            if !resp.status().is_success() {
+                return None;
+            }
+
+            if resp.content_length().is_some_and(|len| len > max_size) {
+                return None;
+            }
+
+            let status = resp.status();
+            let headers = resp.headers().clone();
+            let body = resp.bytes().await.ok()?;
+
+            if body.len() as u64 > max_size {
+                return None;
+            }
+
+            Some(Arc::new(Prefetched {
+                status,
+                headers,
+                body,
+            }))
+        }
+        .boxed()
+        .shared();
+
+        entries.insert(
+            key.clone(),
+            Entry {
+                id,
+                client: client_id.to_string(),
+                future: future.clone(),
+            },
+        );
+
+        // drive the request even if nobody claims it
+        tokio::spawn(future);
+    }
+
+    // drop speculative data that wasn't claimed in time
+    let timeout = limits.timeout;
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        let mut entries = ENTRIES.lock().unwrap();
+        if entries.get(&key).is_some_and(|entry| entry.id == id) {
+            entries.remove(&key);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+
+    /// A URL built the way `proxy` builds upstream URLs.
+    fn proxy_url(base: &str, range: &str) -> Url {
+        let mut url = Url::parse(base).unwrap();
+        let query = QString::new(vec![
+            ("expire", "1700000000"),
+            ("sparams", "expire,ei,ip"),
+            ("mn", "sn-a,sn-b"),
+            ("range", range),
+        ]);
+        url.set_query(Some(&query.to_string()));
+        url
+    }
+
+    #[test]
+    fn next_range_matches_the_url_of_the_next_request() {
+        let base = "https://rr1.googlevideo.com/videoplayback";
+        let next = next_range_url(&proxy_url(base, "0-99"), "0-99", Some(150)).unwrap();
+
+        assert_eq!(next, proxy_url(base, "100-149"));
+        assert_eq!(buffer_key(&next), buffer_key(&proxy_url(base, "100-149")));
+    }
+
+    #[test]
+    fn no_range_past_the_end() {
+        let url = proxy_url("https://rr1.googlevideo.com/videoplayback", "0-99");
+        assert!(next_range_url(&url, "0-99", Some(100)).is_none());
+    }
+
+    #[test]
+    fn no_range_past_the_end_of_u64() {
+        let url = proxy_url("https://rr1.googlevideo.com/videoplayback", "0-99");
+        let max = u64::MAX;
+        assert!(next_range_url(&url, &format!("0-{}", max), None).is_none());
+        assert!(next_range_url(&url, &format!("{}-{}", max - 10, max - 1), None).is_none());
+    }
+
+    #[tokio::test]
+    async fn scheduled_range_is_claimed() {
+        static CLIENT: Lazy<Client> = Lazy::new(Client::new);
+        let limits = Limits {
+            enabled: true,
+            timeout: Duration::from_secs(10),
+            max_per_client: 2,
+            max_total: 64,
+            max_size: 1024,
+        };
+
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let log = requested.clone();
+        let base = test_server::serve(move |request| {
+            log.lock().unwrap().push(request.target);
+            b"0123456789".to_vec()
+        });
+        let base = format!("{}/videoplayback", base);
+
+        let request = Request::new(reqwest::Method::GET, proxy_url(&base, "0-9"));
+        schedule_with(&limits, &CLIENT, &request, "0-9", Some(100), "client");
+
+        let resp = take(&proxy_url(&base, "10-19")).await.expect("not claimed");
+        assert_eq!(resp.bytes().await.unwrap().as_ref(), b"0123456789");
+
+        let requested = requested.lock().unwrap();
+        assert_eq!(requested.len(), 1);
+        assert!(requested[0].contains("range=10-19"));
+    }
+}
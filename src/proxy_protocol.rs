@@ -0,0 +1,268 @@
+use bytes::{Buf, BytesMut};
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8; 6] = b"PROXY ";
+/// Longest possible v1 header, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connection whose PROXY protocol header has been consumed. Bytes read past
+/// the header are served before reading from the connection again.
+pub struct ProxiedStream<IO> {
+    io: IO,
+    buffer: BytesMut,
+}
+
+impl<IO> ProxiedStream<IO> {
+    /// Wraps a connection that doesn't carry a PROXY protocol header.
+    pub fn plain(io: IO) -> Self {
+        ProxiedStream {
+            io,
+            buffer: BytesMut::new(),
+        }
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for ProxiedStream<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.buffer.is_empty() {
+            let len = this.buffer.len().min(buf.remaining());
+            buf.put_slice(&this.buffer[..len]);
+            this.buffer.advance(len);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for ProxiedStream<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Parses a v1 header line without the CRLF.
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let mut parts = line.split(' ').skip(1);
+
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("Unsupported PROXY v1 protocol")),
+    }
+
+    let (Some(source), Some(_), Some(port), Some(_)) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid("Truncated PROXY v1 header"));
+    };
+
+    let source = source
+        .parse::<IpAddr>()
+        .map_err(|_| invalid("Invalid PROXY v1 source address"))?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| invalid("Invalid PROXY v1 source port"))?;
+
+    Ok(Some(SocketAddr::new(source, port)))
+}
+
+/// Parses the address block of a v2 header.
+fn parse_v2(command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    match command {
+        // LOCAL, e.g. health checks of the load balancer itself
+        0x20 => return Ok(None),
+        0x21 => {}
+        _ => return Err(invalid("Unsupported PROXY v2 command")),
+    }
+
+    match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let source = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(source.into(), port)))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let mut source = [0u8; 16];
+            source.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(source).into(), port)))
+        }
+        // AF_UNSPEC or AF_UNIX carry no usable client address
+        0x0 | 0x3 => Ok(None),
+        _ => Err(invalid("Truncated PROXY v2 address block")),
+    }
+}
+
+async fn read_header<IO: AsyncRead + Unpin>(
+    io: &mut IO,
+    buffer: &mut BytesMut,
+) -> io::Result<Option<SocketAddr>> {
+    loop {
+        if buffer.len() >= 16 && buffer.starts_with(V2_SIGNATURE) {
+            if buffer[12] >> 4 != 2 {
+                return Err(invalid("Unsupported PROXY protocol version"));
+            }
+
+            let length = u16::from_be_bytes([buffer[14], buffer[15]]) as usize;
+            if buffer.len() >= 16 + length {
+                let header = buffer.split_to(16 + length);
+                return parse_v2(header[12], header[13], &header[16..]);
+            }
+        } else if buffer.starts_with(V1_PREFIX) {
+            if let Some(end) = buffer.windows(2).position(|window| window == b"\r\n") {
+                let header = buffer.split_to(end + 2);
+                return parse_v1(&header[..end]);
+            }
+
+            if buffer.len() >= V1_MAX_LENGTH {
+                return Err(invalid("PROXY v1 header is too long"));
+            }
+        } else {
+            let prefix_len = buffer.len().min(V2_SIGNATURE.len());
+            let could_be_v2 = V2_SIGNATURE.starts_with(&buffer[..prefix_len]);
+            let could_be_v1 = V1_PREFIX.starts_with(&buffer[..buffer.len().min(V1_PREFIX.len())]);
+
+            if !could_be_v1 && !could_be_v2 {
+                return Err(invalid(
+                    "Connection did not start with a PROXY protocol header",
+                ));
+            }
+        }
+
+        if io.read_buf(buffer).await? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before the PROXY protocol header was complete",
+            ));
+        }
+    }
+}
+
+/// Reads the PROXY protocol v1 or v2 header of a new connection, returning the
+/// wrapped connection and the source address the header announced. Connections
+/// without a valid header are rejected with an error.
+pub async fn accept<IO: AsyncRead + Unpin>(
+    mut io: IO,
+) -> io::Result<(ProxiedStream<IO>, Option<SocketAddr>)> {
+    let mut buffer = BytesMut::with_capacity(V1_MAX_LENGTH);
+
+    let source = tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut io, &mut buffer))
+        .await
+        .map_err(|_| io::Error::new(ErrorKind::TimedOut, "Timed out reading PROXY header"))??;
+
+    Ok((ProxiedStream { io, buffer }, source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut input: &[u8]) -> (io::Result<Option<SocketAddr>>, BytesMut) {
+        let mut buffer = BytesMut::new();
+        let result = read_header(&mut input, &mut buffer).await;
+        buffer.extend_from_slice(input);
+        (result, buffer)
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let (source, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\nGET /").await;
+        assert_eq!(source.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(&rest[..], b"GET /");
+
+        let (source, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 443\r\n").await;
+        assert_eq!(
+            source.unwrap(),
+            Some("[2001:db8::1]:51234".parse().unwrap())
+        );
+
+        let (source, _) = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(source.unwrap(), None);
+
+        assert!(read(b"PROXY TCP4 203.0.113.7\r\n").await.0.is_err());
+        assert!(read(b"PROXY UDP4 203.0.113.7 10.0.0.1 1 2\r\n")
+            .await
+            .0
+            .is_err());
+        assert!(read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 120]].concat())
+            .await
+            .0
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let mut input = v2(
+            0x21,
+            0x11,
+            &[203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 1, 187],
+        );
+        input.extend_from_slice(b"GET /");
+        let (source, rest) = read(&input).await;
+        assert_eq!(source.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(&rest[..], b"GET /");
+
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend_from_slice(&[0; 16]);
+        addresses.extend_from_slice(&[0xc8, 0x22, 1, 187]);
+        let (source, _) = read(&v2(0x21, 0x21, &addresses)).await;
+        assert_eq!(
+            source.unwrap(),
+            Some("[2001:db8::1]:51234".parse().unwrap())
+        );
+
+        // LOCAL connections carry no client
+        let (source, _) = read(&v2(0x20, 0x00, &[])).await;
+        assert_eq!(source.unwrap(), None);
+
+        assert!(read(&v2(0x21, 0x11, &[203, 0, 113])).await.0.is_err());
+        assert!(read(&v2(0x31, 0x11, &[0; 12])).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_without_a_header() {
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.0.is_err());
+        // the connection closes halfway through the header
+        assert!(read(b"PROXY TCP4 203.0.113.7").await.0.is_err());
+    }
+}
@@ -0,0 +1,465 @@
+use crate::api_keys;
+use crate::utils;
+use once_cell::sync::Lazy;
+use std::collections::BTreeSet;
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+struct Keys {
+    /// The first secret signs new URLs, the others are only accepted for verification.
+    secrets: Vec<String>,
+    /// Unix timestamp after which previous secrets stop being accepted, so the
+    /// cutoff survives restarts.
+    grace_until: Option<u64>,
+}
+
+struct Scheme {
+    /// Sign rewritten URLs with `qh2` instead of the legacy `qhash`.
+    sign_v2: bool,
+    /// Reject requests only carrying a legacy `qhash`, for after the migration.
+    require_v2: bool,
+    /// Number of hex characters of the v2 MAC.
+    v2_length: usize,
+    /// Lifetime of v2 signatures in seconds.
+    v2_ttl: u64,
+    /// Bind signatures to the network of the client they were issued to, as
+    /// resolved through `TRUSTED_PROXIES` and `CLIENT_IP_HEADER`.
+    bind_ip: bool,
+    ipv4_prefix: u32,
+    ipv6_prefix: u32,
+}
+
+static SCHEME: Lazy<Scheme> = Lazy::new(|| Scheme {
+    sign_v2: utils::get_env_bool("HASH_SIGN_V2"),
+    require_v2: utils::get_env_bool("HASH_REQUIRE_V2"),
+    v2_length: utils::get_env_number::<usize>("HASH_V2_LENGTH", 32).clamp(16, 64),
+    v2_ttl: utils::get_env_number("HASH_V2_TTL", 6 * 60 * 60),
+    bind_ip: utils::get_env_bool("HASH_BIND_IP"),
+    ipv4_prefix: utils::get_env_number::<u32>("HASH_BIND_IPV4_PREFIX", 24).min(32),
+    ipv6_prefix: utils::get_env_number::<u32>("HASH_BIND_IPV6_PREFIX", 48).min(128),
+});
+
+/// Pseudo parameter carrying the client network when signatures are bound to it.
+/// It is filtered from the query string, so clients can't provide their own.
+const CLIENT_PAIR: &str = "\0client";
+
+/// Tolerated difference between the clocks of the signer and this proxy.
+const CLOCK_SKEW: u64 = 60;
+
+static KEYS: Lazy<Option<Keys>> = Lazy::new(|| {
+    // the current secret is taken as is, so existing secrets keep working, while
+    // previous ones are a comma separated list and can't contain commas
+    let secret = env::var("HASH_SECRET").ok()?;
+    if secret.contains(',') {
+        tracing::warn!(
+            "HASH_SECRET contains a comma, it can't be moved to HASH_SECRET_PREVIOUS when rotated"
+        );
+    }
+    let mut secrets = vec![secret];
+
+    secrets.extend(
+        env::var("HASH_SECRET_PREVIOUS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|secret| secret.trim().to_string())
+            .filter(|secret| !secret.is_empty()),
+    );
+
+    let grace_until = env::var("HASH_SECRET_GRACE_UNTIL")
+        .ok()
+        .and_then(|timestamp| timestamp.parse().ok());
+
+    Some(Keys {
+        secrets,
+        grace_until,
+    })
+});
+
+pub fn is_enabled() -> bool {
+    KEYS.is_some()
+}
+
+/// Number of secrets accepted for verification, including the current one.
+pub fn key_count() -> usize {
+    KEYS.as_ref().map(|keys| keys.secrets.len()).unwrap_or(0)
+}
+
+fn legacy_hash(pairs: &[(&str, &str)], path: &str, secret: &str) -> String {
+    // Store sorted key-value pairs
+    let set = pairs.iter().collect::<BTreeSet<_>>();
+
+    let mut hasher = blake3::Hasher::new();
+
+    for (key, value) in set {
+        hasher.update(key.as_bytes());
+        hasher.update(value.as_bytes());
+    }
+
+    hasher.update(signed_path(path).as_bytes());
+
+    hasher.update(secret.as_bytes());
+
+    let hash = hasher.finalize().to_hex();
+
+    hash[..8].to_owned()
+}
+
+/// Computes the v2 MAC over the canonical form of a request:
+///
+/// ```text
+/// "qh2" NUL issued NUL expires NUL path NUL
+/// followed by, for each (key, value) pair sorted by key then value,
+/// len(key) ":" key len(value) ":" value
+/// ```
+///
+/// Lengths are decimal byte counts, so no escaping is needed. The MAC is a
+/// BLAKE3 keyed hash with a key derived from the secret, hex encoded and
+/// truncated to `HASH_V2_LENGTH` characters.
+fn v2_mac(
+    pairs: &[(&str, &str)],
+    path: &str,
+    issued: u64,
+    expires: u64,
+    secret: &str,
+    length: usize,
+) -> String {
+    let mut pairs = pairs.to_vec();
+    pairs.sort();
+
+    let key = blake3::derive_key("piped-proxy qh2 v1", secret.as_bytes());
+    let mut hasher = blake3::Hasher::new_keyed(&key);
+
+    hasher.update(format!("qh2\0{}\0{}\0", issued, expires).as_bytes());
+    hasher.update(signed_path(path).as_bytes());
+    hasher.update(b"\0");
+
+    for (key, value) in pairs {
+        hasher.update(format!("{}:", key.len()).as_bytes());
+        hasher.update(key.as_bytes());
+        hasher.update(format!("{}:", value.len()).as_bytes());
+        hasher.update(value.as_bytes());
+    }
+
+    let hash = hasher.finalize().to_hex();
+
+    hash[..length].to_owned()
+}
+
+// Only sign the part of the path before "/range/", including the "/"
+// This is done for DASH streams for the manifests provided by YouTube
+fn signed_path(path: &str) -> &str {
+    match path.find("/range/") {
+        Some(position) => &path[..(position + 1)],
+        None => path,
+    }
+}
+
+fn signed_pairs<'a>(pairs: impl Iterator<Item = (&'a str, &'a str)>) -> Vec<(&'a str, &'a str)> {
+    pairs
+        .filter(|(key, _)| {
+            !matches!(
+                *key,
+                "qhash" | "qh2" | "range" | "rewrite" | api_keys::QUERY_PARAM | CLIENT_PAIR
+            )
+        })
+        .collect()
+}
+
+/// The network prefix of the client, if signatures are bound to it.
+fn client_network(scheme: &Scheme, client_ip: Option<IpAddr>) -> Option<String> {
+    if !scheme.bind_ip {
+        return None;
+    }
+
+    let network = match client_ip.map(|ip| ip.to_canonical()) {
+        Some(IpAddr::V4(ip)) => {
+            let prefix = scheme.ipv4_prefix;
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            format!("{}/{}", Ipv4Addr::from(u32::from(ip) & mask), prefix)
+        }
+        Some(IpAddr::V6(ip)) => {
+            let prefix = scheme.ipv6_prefix;
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            format!("{}/{}", Ipv6Addr::from(u128::from(ip) & mask), prefix)
+        }
+        None => "unknown".to_string(),
+    };
+
+    Some(network)
+}
+
+/// Signs the query and path with the current secret, returning the name and
+/// value of the query parameter to add.
+pub fn sign<'a>(
+    pairs: impl Iterator<Item = (&'a str, &'a str)>,
+    path: &str,
+    client_ip: Option<IpAddr>,
+) -> Option<(&'static str, String)> {
+    Some(sign_with(KEYS.as_ref()?, &SCHEME, pairs, path, client_ip))
+}
+
+fn sign_with<'a>(
+    keys: &Keys,
+    scheme: &Scheme,
+    pairs: impl Iterator<Item = (&'a str, &'a str)>,
+    path: &str,
+    client_ip: Option<IpAddr>,
+) -> (&'static str, String) {
+    let network = client_network(scheme, client_ip);
+    let mut pairs = signed_pairs(pairs);
+    if let Some(network) = network.as_deref() {
+        pairs.push((CLIENT_PAIR, network));
+    }
+
+    if scheme.sign_v2 {
+        let issued = utils::now();
+        let expires = issued + scheme.v2_ttl;
+        let secret = &keys.secrets[0];
+        let mac = v2_mac(&pairs, path, issued, expires, secret, scheme.v2_length);
+        ("qh2", format!("{}.{}.{}", issued, expires, mac))
+    } else {
+        ("qhash", legacy_hash(&pairs, path, &keys.secrets[0]))
+    }
+}
+
+/// Checks the signature of a request against every accepted secret, preferring
+/// `qh2` over the legacy `qhash`. Returns the version of the key that validated it.
+pub fn verify(
+    pairs: &[(String, String)],
+    path: &str,
+    client_ip: Option<IpAddr>,
+) -> Result<usize, &'static str> {
+    let Some(keys) = KEYS.as_ref() else {
+        return Ok(0);
+    };
+
+    verify_with(keys, &SCHEME, pairs, path, client_ip)
+}
+
+fn verify_with(
+    keys: &Keys,
+    scheme: &Scheme,
+    pairs: &[(String, String)],
+    path: &str,
+    client_ip: Option<IpAddr>,
+) -> Result<usize, &'static str> {
+    let in_grace = keys
+        .grace_until
+        .is_none_or(|grace_until| utils::now() < grace_until);
+    let secrets = &keys.secrets[..if in_grace { keys.secrets.len() } else { 1 }];
+
+    let get = |name: &str| {
+        pairs
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let network = client_network(scheme, client_ip);
+    let mut signed = signed_pairs(
+        pairs
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str())),
+    );
+    if let Some(network) = network.as_deref() {
+        signed.push((CLIENT_PAIR, network));
+    }
+
+    let version = if let Some(qh2) = get("qh2") {
+        let mut parts = qh2.splitn(3, '.');
+        let (Some(issued), Some(expires), Some(mac)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err("Invalid qh2 provided");
+        };
+        let (Ok(issued), Ok(expires)) = (issued.parse::<u64>(), expires.parse::<u64>()) else {
+            return Err("Invalid qh2 provided");
+        };
+
+        if mac.len() != scheme.v2_length {
+            return Err("Invalid qh2 provided");
+        }
+
+        let now = utils::now();
+        if issued > now + CLOCK_SKEW || now > expires {
+            return Err("qh2 expired");
+        }
+
+        secrets.iter().position(|secret| {
+            utils::constant_time_eq(
+                v2_mac(&signed, path, issued, expires, secret, scheme.v2_length).as_bytes(),
+                mac.as_bytes(),
+            )
+        })
+    } else if scheme.require_v2 {
+        return Err("No qh2 provided");
+    } else {
+        let Some(qhash) = get("qhash") else {
+            return Err("No qhash provided");
+        };
+
+        if qhash.len() != 8 {
+            return Err("Invalid qhash provided");
+        }
+
+        secrets.iter().position(|secret| {
+            utils::constant_time_eq(
+                legacy_hash(&signed, path, secret).as_bytes(),
+                qhash.as_bytes(),
+            )
+        })
+    };
+
+    let Some(version) = version else {
+        return Err("Invalid qhash provided");
+    };
+
+    if version > 0 {
+        tracing::debug!("qhash validated by previous key version {}", version);
+    }
+
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "/videoplayback";
+
+    fn keys(secrets: &[&str], grace_until: Option<u64>) -> Keys {
+        Keys {
+            secrets: secrets.iter().map(|secret| secret.to_string()).collect(),
+            grace_until,
+        }
+    }
+
+    fn scheme(sign_v2: bool) -> Scheme {
+        Scheme {
+            sign_v2,
+            require_v2: false,
+            v2_length: 32,
+            v2_ttl: 60,
+            bind_ip: false,
+            ipv4_prefix: 24,
+            ipv6_prefix: 48,
+        }
+    }
+
+    /// The query of a signed URL, with whatever the player adds to it.
+    fn signed_query(
+        keys: &Keys,
+        scheme: &Scheme,
+        path: &str,
+        extra: &[(&str, &str)],
+        client_ip: Option<IpAddr>,
+    ) -> Vec<(String, String)> {
+        let pairs = [
+            ("host", "rr1.googlevideo.com"),
+            ("id", "abc"),
+            ("itag", "18"),
+        ];
+        let (name, signature) = sign_with(keys, scheme, pairs.into_iter(), path, client_ip);
+
+        pairs
+            .iter()
+            .chain(extra)
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .chain([(name.to_string(), signature)])
+            .collect()
+    }
+
+    #[test]
+    fn v2_signatures_round_trip() {
+        let keys = keys(&["secret"], None);
+        let scheme = scheme(true);
+
+        let query = signed_query(&keys, &scheme, PATH, &[("range", "0-99")], None);
+        assert!(query.iter().any(|(key, _)| key == "qh2"));
+        assert_eq!(verify_with(&keys, &scheme, &query, PATH, None), Ok(0));
+        assert!(verify_with(&keys, &scheme, &query, "/api/manifest/hls", None).is_err());
+
+        let mut tampered = query.clone();
+        tampered[1].1 = "xyz".to_string();
+        assert!(verify_with(&keys, &scheme, &tampered, PATH, None).is_err());
+
+        // DASH segments are signed up to /range/
+        let segments = "/videoplayback/range/";
+        let query = signed_query(&keys, &scheme, segments, &[], None);
+        let segment = format!("{}100-199", segments);
+        assert_eq!(verify_with(&keys, &scheme, &query, &segment, None), Ok(0));
+    }
+
+    #[test]
+    fn expired_v2_signatures_are_rejected() {
+        let keys = keys(&["secret"], None);
+        let scheme = scheme(true);
+
+        let pairs = [("host", "rr1.googlevideo.com")];
+        let mac = v2_mac(&pairs, PATH, 1, 2, "secret", scheme.v2_length);
+        let query = [
+            ("host", "rr1.googlevideo.com"),
+            ("qh2", &format!("1.2.{}", mac)),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+
+        assert_eq!(
+            verify_with(&keys, &scheme, &query, PATH, None),
+            Err("qh2 expired")
+        );
+    }
+
+    #[test]
+    fn legacy_signatures_round_trip() {
+        let keys = keys(&["secret"], None);
+        let query = signed_query(&keys, &scheme(false), PATH, &[("rewrite", "false")], None);
+        assert!(query.iter().any(|(key, _)| key == "qhash"));
+        assert_eq!(
+            verify_with(&keys, &scheme(false), &query, PATH, None),
+            Ok(0)
+        );
+
+        let require_v2 = Scheme {
+            require_v2: true,
+            ..scheme(false)
+        };
+        assert_eq!(
+            verify_with(&keys, &require_v2, &query, PATH, None),
+            Err("No qh2 provided")
+        );
+    }
+
+    #[test]
+    fn signatures_bound_to_the_client_network() {
+        let keys = keys(&["secret"], None);
+        let scheme = Scheme {
+            bind_ip: true,
+            ..scheme(true)
+        };
+        let client = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+
+        let query = signed_query(&keys, &scheme, PATH, &[], client("203.0.113.7"));
+        let verify = |ip| verify_with(&keys, &scheme, &query, PATH, client(ip));
+        assert_eq!(verify("203.0.113.99"), Ok(0));
+        assert_eq!(verify("::ffff:203.0.113.99"), Ok(0));
+        assert!(verify("198.51.100.7").is_err());
+
+        let query = signed_query(&keys, &scheme, PATH, &[], client("2001:db8:1:2::1"));
+        let verify = |ip| verify_with(&keys, &scheme, &query, PATH, client(ip));
+        assert_eq!(verify("2001:db8:1:ffff::1"), Ok(0));
+        assert!(verify("2001:db8:2::1").is_err());
+    }
+
+    #[test]
+    fn previous_secrets_verify_during_the_grace_period() {
+        let scheme = scheme(true);
+        let query = signed_query(&keys(&["old"], None), &scheme, PATH, &[], None);
+
+        let rotated = keys(&["new", "old"], None);
+        assert_eq!(verify_with(&rotated, &scheme, &query, PATH, None), Ok(1));
+
+        let expired = keys(&["new", "old"], Some(utils::now()));
+        assert!(verify_with(&expired, &scheme, &query, PATH, None).is_err());
+
+        let unknown = keys(&["new"], None);
+        assert!(verify_with(&unknown, &scheme, &query, PATH, None).is_err());
+    }
+}
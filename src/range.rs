@@ -0,0 +1,247 @@
+use actix_web::http::header::HttpDate;
+use actix_web::HttpRequest;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A single range of a `Range: bytes=...` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RangeSpec {
+    /// `first-last`
+    Bounded(u64, u64),
+    /// `first-`
+    From(u64),
+    /// `-length`, the last bytes of the content
+    Suffix(u64),
+}
+
+/// An inclusive byte range within content of a known size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Parses the `start-end` form used by the `range` query parameter of
+    /// googlevideo, clamping the end to the size of the content.
+    pub fn from_query(range: &str, total: u64) -> Option<ByteRange> {
+        let range = range.strip_prefix("bytes=").unwrap_or(range);
+        let spec = parse_spec(range)?;
+        spec.resolve(total)
+    }
+
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+
+    pub fn to_query(self) -> String {
+        format!("{}-{}", self.start, self.end)
+    }
+}
+
+impl RangeSpec {
+    /// The bytes selected within content of `total` bytes, or `None` if the
+    /// range is unsatisfiable.
+    pub fn resolve(self, total: u64) -> Option<ByteRange> {
+        let last = total.checked_sub(1)?;
+        match self {
+            RangeSpec::Bounded(start, end) if start <= last => Some(ByteRange {
+                start,
+                end: end.min(last),
+            }),
+            RangeSpec::From(start) if start <= last => Some(ByteRange { start, end: last }),
+            RangeSpec::Suffix(length) if length > 0 => Some(ByteRange {
+                start: total.saturating_sub(length),
+                end: last,
+            }),
+            _ => None,
+        }
+    }
+
+    fn to_header(self) -> String {
+        match self {
+            RangeSpec::Bounded(start, end) => format!("bytes={}-{}", start, end),
+            RangeSpec::From(start) => format!("bytes={}-", start),
+            RangeSpec::Suffix(length) => format!("bytes=-{}", length),
+        }
+    }
+}
+
+fn parse_spec(spec: &str) -> Option<RangeSpec> {
+    let (start, end) = spec.trim().split_once('-')?;
+
+    if start.is_empty() {
+        return end.parse().ok().map(RangeSpec::Suffix);
+    }
+
+    let start = start.parse().ok()?;
+    if end.is_empty() {
+        return Some(RangeSpec::From(start));
+    }
+
+    let end = end.parse().ok()?;
+    (end >= start).then_some(RangeSpec::Bounded(start, end))
+}
+
+/// Parses a `Range` header. Returns `None` for anything malformed, in which case
+/// the header has to be ignored and the full content served.
+pub fn parse_header(header: &str) -> Option<Vec<RangeSpec>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+
+    specs
+        .split(',')
+        .filter(|spec| !spec.trim().is_empty())
+        .map(parse_spec)
+        .collect::<Option<Vec<_>>>()
+        .filter(|specs| !specs.is_empty())
+}
+
+/// Merges all requested ranges into the one range spanning them. Used where the
+/// upstream only accepts a single range; the client gets a superset of what it
+/// asked for, which RFC 9110 permits.
+pub fn coalesce(specs: &[RangeSpec]) -> Option<RangeSpec> {
+    let suffix = specs
+        .iter()
+        .filter_map(|spec| match spec {
+            RangeSpec::Suffix(length) => Some(*length),
+            _ => None,
+        })
+        .max();
+
+    let start = specs
+        .iter()
+        .filter_map(|spec| match spec {
+            RangeSpec::Bounded(start, _) | RangeSpec::From(start) => Some(*start),
+            RangeSpec::Suffix(_) => None,
+        })
+        .min();
+
+    let Some(start) = start else {
+        return suffix.map(RangeSpec::Suffix);
+    };
+
+    // a suffix always reaches the end of the content
+    let open_ended =
+        suffix.is_some() || specs.iter().any(|spec| matches!(spec, RangeSpec::From(_)));
+
+    if open_ended {
+        return Some(RangeSpec::From(start));
+    }
+
+    let end = specs
+        .iter()
+        .filter_map(|spec| match spec {
+            RangeSpec::Bounded(_, end) => Some(*end),
+            _ => None,
+        })
+        .max()?;
+
+    Some(RangeSpec::Bounded(start, end))
+}
+
+/// Resolves the requested ranges within content of `total` bytes and merges them
+/// into one. `None` means none of them is satisfiable.
+pub fn resolve(specs: &[RangeSpec], total: u64) -> Option<ByteRange> {
+    let ranges = specs
+        .iter()
+        .filter_map(|spec| spec.resolve(total))
+        .collect::<Vec<_>>();
+
+    Some(ByteRange {
+        start: ranges.iter().map(|range| range.start).min()?,
+        end: ranges.iter().map(|range| range.end).max()?,
+    })
+}
+
+/// Whether the `If-Range` precondition of the request holds, i.e. the `Range`
+/// header may be honoured. Only a date matching `last_modified` exactly
+/// validates, as we have no entity tags to compare against.
+pub fn if_range_matches(req: &HttpRequest, last_modified: Option<SystemTime>) -> bool {
+    let Some(if_range) = req.headers().get("if-range") else {
+        return true;
+    };
+
+    let (Some(if_range), Some(last_modified)) = (
+        if_range
+            .to_str()
+            .ok()
+            .and_then(|date| date.parse::<HttpDate>().ok()),
+        last_modified,
+    ) else {
+        return false;
+    };
+
+    let seconds = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .ok()
+    };
+
+    seconds(if_range.into()) == seconds(last_modified)
+}
+
+/// The `Range` header to send upstream instead of the one of the client.
+pub fn upstream_header(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get("range")?.to_str().ok()?;
+    parse_header(header)
+        .as_deref()
+        .and_then(coalesce)
+        .map(RangeSpec::to_header)
+}
+
+/// `Content-Range` of a 416 response for content of `total` bytes.
+pub fn unsatisfied_content_range(total: u64) -> String {
+    format!("bytes */{}", total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_range_headers() {
+        assert_eq!(
+            parse_header("bytes=0-99"),
+            Some(vec![RangeSpec::Bounded(0, 99)])
+        );
+        assert_eq!(
+            parse_header("bytes=100-, -50"),
+            Some(vec![RangeSpec::From(100), RangeSpec::Suffix(50)])
+        );
+        assert_eq!(
+            parse_header("bytes=0-99,"),
+            Some(vec![RangeSpec::Bounded(0, 99)])
+        );
+
+        assert_eq!(parse_header("bytes=99-0"), None);
+        assert_eq!(parse_header("bytes=a-b"), None);
+        assert_eq!(parse_header("bytes="), None);
+        assert_eq!(parse_header("items=0-99"), None);
+    }
+
+    #[test]
+    fn resolves_ranges_within_the_content() {
+        let range = |start, end| Some(ByteRange { start, end });
+
+        assert_eq!(resolve(&[RangeSpec::Bounded(0, 999)], 100), range(0, 99));
+        assert_eq!(resolve(&[RangeSpec::From(90)], 100), range(90, 99));
+        assert_eq!(resolve(&[RangeSpec::Suffix(10)], 100), range(90, 99));
+        assert_eq!(resolve(&[RangeSpec::Suffix(1000)], 100), range(0, 99));
+        assert_eq!(
+            resolve(&[RangeSpec::Bounded(0, 9), RangeSpec::Bounded(50, 59)], 100),
+            range(0, 59)
+        );
+        // unsatisfiable ranges are left out, unless nothing else remains
+        assert_eq!(
+            resolve(&[RangeSpec::Bounded(200, 299), RangeSpec::From(10)], 100),
+            range(10, 99)
+        );
+
+        assert_eq!(resolve(&[RangeSpec::From(100)], 100), None);
+        assert_eq!(resolve(&[RangeSpec::Suffix(0)], 100), None);
+        assert_eq!(resolve(&[RangeSpec::Bounded(0, 9)], 0), None);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::metrics::Route;
+use crate::utils;
+use futures_util::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Classes of requests limited independently of each other.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LimitClass {
+    Videoplayback,
+    Image,
+    Manifest,
+}
+
+impl LimitClass {
+    fn env_name(self) -> &'static str {
+        match self {
+            LimitClass::Videoplayback => "VIDEOPLAYBACK",
+            LimitClass::Image => "IMAGES",
+            LimitClass::Manifest => "MANIFESTS",
+        }
+    }
+
+    /// The class a request is limited in, `None` for requests that aren't.
+    pub fn of(route: Route) -> Option<LimitClass> {
+        match route {
+            Route::Videoplayback | Route::Ump => Some(LimitClass::Videoplayback),
+            Route::Hls | Route::Dash => Some(LimitClass::Manifest),
+            Route::Image => Some(LimitClass::Image),
+            Route::Other => None,
+        }
+    }
+}
+
+struct Limits {
+    /// Tokens added per second, `None` for no rate limit.
+    rate: Option<f64>,
+    burst: f64,
+    /// Maximum number of concurrent streams per client.
+    max_streams: Option<usize>,
+}
+
+impl Limits {
+    fn from_env(class: LimitClass) -> Limits {
+        let name = class.env_name();
+
+        let rate = env::var(format!("RATE_LIMIT_{}", name))
+            .ok()
+            .and_then(|rate| rate.parse::<f64>().ok())
+            .filter(|rate| *rate > 0.0);
+        let burst = utils::get_env_number(
+            &format!("RATE_LIMIT_{}_BURST", name),
+            rate.map(|rate| (rate * 2.0).ceil()).unwrap_or(1.0),
+        );
+        let max_streams = env::var(format!("MAX_STREAMS_{}", name))
+            .ok()
+            .and_then(|max| max.parse::<usize>().ok());
+
+        Limits {
+            rate,
+            burst,
+            max_streams,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Default)]
+struct ClientState {
+    buckets: HashMap<LimitClass, Bucket>,
+    streams: HashMap<LimitClass, usize>,
+}
+
+const CLASSES: [LimitClass; 3] = [
+    LimitClass::Videoplayback,
+    LimitClass::Image,
+    LimitClass::Manifest,
+];
+
+static LIMITS: Lazy<HashMap<LimitClass, Limits>> = Lazy::new(|| {
+    CLASSES
+        .iter()
+        .map(|class| (*class, Limits::from_env(*class)))
+        .collect()
+});
+
+static CLIENTS: Lazy<Mutex<HashMap<IpAddr, ClientState>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Clients are forgotten once their state is idle and this many are tracked.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Held for as long as a stream is open, releasing its slot when dropped.
+pub struct StreamGuard {
+    client: IpAddr,
+    class: LimitClass,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut clients = CLIENTS.lock().unwrap();
+        if let Some(state) = clients.get_mut(&self.client) {
+            if let Some(streams) = state.streams.get_mut(&self.class) {
+                *streams = streams.saturating_sub(1);
+            }
+        }
+    }
+}
+
+pub enum Rejection {
+    /// The client has to wait this long before the next request is accepted.
+    RateLimited(Duration),
+    TooManyStreams,
+}
+
+impl Rejection {
+    /// Value of the `Retry-After` header in seconds.
+    pub fn retry_after(&self) -> u64 {
+        match self {
+            Rejection::RateLimited(wait) => (wait.as_secs_f64().ceil() as u64).max(1),
+            Rejection::TooManyStreams => 1,
+        }
+    }
+}
+
+/// Takes a token from the client's bucket and a stream slot for the class.
+pub fn acquire(
+    client: Option<IpAddr>,
+    class: LimitClass,
+) -> Result<Option<StreamGuard>, Rejection> {
+    let limits = &LIMITS[&class];
+
+    if limits.rate.is_none() && limits.max_streams.is_none() {
+        return Ok(None);
+    }
+
+    // requests over Unix sockets without a client IP header can't be told apart
+    let Some(client) = client else {
+        return Ok(None);
+    };
+
+    let now = Instant::now();
+    let mut clients = CLIENTS.lock().unwrap();
+
+    if clients.len() > PRUNE_THRESHOLD {
+        clients.retain(|_, state| {
+            state.streams.values().any(|streams| *streams > 0)
+                || state.buckets.iter().any(|(class, bucket)| {
+                    let limits = &LIMITS[class];
+                    let rate = limits.rate.unwrap_or(f64::INFINITY);
+                    bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate
+                        < limits.burst
+                })
+        });
+    }
+
+    let state = clients.entry(client).or_default();
+
+    if let Some(max_streams) = limits.max_streams {
+        if state.streams.get(&class).copied().unwrap_or(0) >= max_streams {
+            return Err(Rejection::TooManyStreams);
+        }
+    }
+
+    if let Some(rate) = limits.rate {
+        let bucket = state.buckets.entry(class).or_insert(Bucket {
+            tokens: limits.burst,
+            last: now,
+        });
+
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate)
+            .min(limits.burst);
+        bucket.last = now;
+
+        if bucket.tokens < 1.0 {
+            let wait = (1.0 - bucket.tokens) / rate;
+            return Err(Rejection::RateLimited(Duration::from_secs_f64(wait)));
+        }
+
+        bucket.tokens -= 1.0;
+    }
+
+    if limits.max_streams.is_none() {
+        return Ok(None);
+    }
+
+    *state.streams.entry(class).or_default() += 1;
+
+    Ok(Some(StreamGuard { client, class }))
+}
+
+/// Keeps the guard alive until the stream is dropped.
+pub fn guard_stream<S: Stream>(
+    stream: S,
+    guard: Option<StreamGuard>,
+) -> impl Stream<Item = S::Item> {
+    stream.map(move |item| {
+        let _ = &guard;
+        item
+    })
+}
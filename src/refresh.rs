@@ -0,0 +1,211 @@
+use crate::utils;
+use once_cell::sync::Lazy;
+use qstring::QString;
+use reqwest::{Client, Url};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::Instrument;
+
+/// Endpoint of the Piped backend handing out fresh stream URLs, asked with
+/// `GET <endpoint>?id=<id>&itag=<itag>` and answering with the URL as text.
+static ENDPOINT: Lazy<Option<Url>> = Lazy::new(|| {
+    env::var("URL_REFRESH_ENDPOINT").ok().map(|endpoint| {
+        Url::parse(&endpoint)
+            .unwrap_or_else(|e| panic!("Invalid URL_REFRESH_ENDPOINT {}: {}", endpoint, e))
+    })
+});
+
+static REFRESH_CLIENT: Lazy<Client> = Lazy::new(|| {
+    Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap()
+});
+
+/// A stream, identified by the `id` and `itag` of its URL.
+type StreamKey = (String, String);
+
+/// Fresh URLs with their expiry, kept until they expire themselves so a player
+/// still holding the old URL doesn't cause a refresh on every range.
+static CACHE: Lazy<Mutex<HashMap<StreamKey, (Url, u64)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn is_enabled() -> bool {
+    ENDPOINT.is_some()
+}
+
+fn expiry(url: &Url) -> u64 {
+    url.query_pairs()
+        .find(|(key, _)| key == "expire")
+        .and_then(|(_, expire)| expire.parse().ok())
+        .unwrap_or(0)
+}
+
+async fn fetch(endpoint: &Url, id: &str, itag: &str) -> Option<Url> {
+    let mut endpoint = endpoint.clone();
+    endpoint
+        .query_pairs_mut()
+        .append_pair("id", id)
+        .append_pair("itag", itag);
+
+    let request = REFRESH_CLIENT.get(endpoint);
+
+    #[cfg(feature = "otel")]
+    let request = {
+        let mut headers = reqwest::header::HeaderMap::new();
+        crate::otel::inject(&mut headers);
+        request.headers(headers)
+    };
+
+    let resp = match request.send().await {
+        Ok(resp) if resp.status().is_success() => resp,
+        Ok(resp) => {
+            tracing::warn!(
+                "Could not refresh URL of {} ({}): {}",
+                id,
+                itag,
+                resp.status()
+            );
+            return None;
+        }
+        Err(e) => {
+            tracing::warn!(
+                "Could not refresh URL of {} ({}): {}",
+                id,
+                itag,
+                crate::logging::redact_error(&e)
+            );
+            return None;
+        }
+    };
+
+    let url = Url::parse(resp.text().await.ok()?.trim()).ok()?;
+
+    // the backend is trusted, but its answer still has to be something we'd proxy
+    let valid = url.scheme() == "https"
+        && url.path() == "/videoplayback"
+        && url.host_str().is_some_and(crate::is_domain_allowed);
+    if !valid {
+        tracing::warn!("Ignoring invalid refreshed URL of {} ({})", id, itag);
+        return None;
+    }
+
+    Some(url)
+}
+
+/// Gets a fresh URL for the stream of an expired or rejected `/videoplayback`
+/// URL. The query of the original URL is given, and the client's own parameters
+/// such as `range` are carried over.
+pub async fn refresh(query: &QString) -> Option<Url> {
+    refresh_from(ENDPOINT.as_ref()?, query).await
+}
+
+async fn refresh_from(endpoint: &Url, query: &QString) -> Option<Url> {
+    let id = query.get("id")?.to_string();
+    let itag = query.get("itag")?.to_string();
+
+    let cached = CACHE
+        .lock()
+        .unwrap()
+        .get(&(id.clone(), itag.clone()))
+        .filter(|(_, expires)| *expires > utils::now())
+        .map(|(url, _)| url.clone());
+
+    let mut url = match cached {
+        Some(url) => url,
+        None => {
+            let url = fetch(endpoint, &id, &itag)
+                .instrument(tracing::info_span!("url_refresh", id = %id, itag = %itag))
+                .await?;
+
+            let mut cache = CACHE.lock().unwrap();
+            let now = utils::now();
+            cache.retain(|_, (_, expires)| *expires > now);
+            cache.insert((id, itag), (url.clone(), expiry(&url)));
+
+            url
+        }
+    };
+
+    let params = utils::CLIENT_PARAMS
+        .iter()
+        .filter_map(|key| query.get(key).map(|value| (*key, value)))
+        .filter(|(key, _)| !url.query_pairs().any(|(existing, _)| existing == *key))
+        .collect::<Vec<_>>();
+    url.query_pairs_mut().extend_pairs(params);
+
+    Some(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+    use std::sync::Arc;
+
+    /// A backend handing out a fresh URL for `id=abc` and one on a host we
+    /// don't proxy for `id=evil`, logging the ids it was asked for.
+    fn stub_endpoint() -> (Url, Arc<Mutex<Vec<String>>>) {
+        let requested = Arc::new(Mutex::new(Vec::new()));
+
+        let log = requested.clone();
+        let base = test_server::serve(move |request| {
+            let query = QString::from(request.target.split_once('?').unwrap_or_default().1);
+            let id = query.get("id").unwrap_or_default().to_string();
+            log.lock().unwrap().push(id.clone());
+
+            let host = if id == "evil" {
+                "evil.example"
+            } else {
+                "rr1.googlevideo.com"
+            };
+            format!(
+                "https://{}/videoplayback?id={}&itag={}&expire={}",
+                host,
+                id,
+                query.get("itag").unwrap_or_default(),
+                utils::now() + 3600
+            )
+            .into_bytes()
+        });
+
+        let endpoint = Url::parse(&format!("{}/refresh", base)).unwrap();
+        (endpoint, requested)
+    }
+
+    #[tokio::test]
+    async fn refreshed_urls_are_cached_and_validated() {
+        let (endpoint, requested) = stub_endpoint();
+
+        // an expired URL, as requested by the client
+        let expired = QString::from("id=abc&itag=18&expire=1&range=0-99&host=rr1.googlevideo.com");
+        let fresh = refresh_from(&endpoint, &expired)
+            .await
+            .expect("not refreshed");
+        assert_eq!(fresh.host_str(), Some("rr1.googlevideo.com"));
+        assert!(expiry(&fresh) > utils::now());
+        assert_eq!(
+            QString::from(fresh.query().unwrap()).get("range"),
+            Some("0-99")
+        );
+        assert!(!fresh.query_pairs().any(|(key, _)| key == "host"));
+
+        // the upstream URL of a request that got a 403, served from the cache
+        let rejected = format!("id=abc&itag=18&expire={}&range=100-199", utils::now() + 60);
+        let retried = refresh_from(&endpoint, &QString::from(rejected.as_str()))
+            .await
+            .expect("not refreshed");
+        assert_eq!(
+            QString::from(retried.query().unwrap()).get("range"),
+            Some("100-199")
+        );
+        assert_eq!(*requested.lock().unwrap(), ["abc"]);
+
+        // URLs on hosts we don't proxy are refused
+        let evil = QString::from("id=evil&itag=18&expire=1");
+        assert!(refresh_from(&endpoint, &evil).await.is_none());
+        assert_eq!(*requested.lock().unwrap(), ["abc", "evil"]);
+    }
+}
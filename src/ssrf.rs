@@ -0,0 +1,147 @@
+use crate::cidr::Cidr;
+use once_cell::sync::Lazy;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::Url;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+/// Networks upstream connections must never reach.
+const BLOCKED_NETWORKS: [&str; 17] = [
+    // "this" network and unspecified addresses
+    "0.0.0.0/8",
+    "::/128",
+    // loopback
+    "127.0.0.0/8",
+    "::1/128",
+    // private networks
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "fc00::/7",
+    // CGNAT
+    "100.64.0.0/10",
+    // link-local, including cloud metadata services at 169.254.169.254
+    "169.254.0.0/16",
+    "fe80::/10",
+    // benchmarking, reserved and broadcast
+    "198.18.0.0/15",
+    "240.0.0.0/4",
+    // multicast
+    "224.0.0.0/4",
+    "ff00::/8",
+    // NAT64 and 6to4 can embed any of the above
+    "64:ff9b::/96",
+    "2002::/16",
+];
+
+static BLOCKED: Lazy<Vec<Cidr>> = Lazy::new(|| Cidr::parse_list(&BLOCKED_NETWORKS.join(",")));
+
+/// Networks exempt from the guard, e.g. a local cache in front of the CDN.
+static ALLOWED: Lazy<Vec<Cidr>> = Lazy::new(|| {
+    env::var("SSRF_ALLOWED_NETWORKS")
+        .map(|list| Cidr::parse_list(&list))
+        .unwrap_or_default()
+});
+
+pub fn is_blocked(ip: IpAddr) -> bool {
+    BLOCKED.iter().any(|cidr| cidr.contains(ip)) && !ALLOWED.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// Returned when every address of an upstream host is off limits.
+#[derive(Debug)]
+pub struct BlockedDestination {
+    host: String,
+    addr: IpAddr,
+}
+
+impl fmt::Display for BlockedDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "refusing to connect to {}: it resolves to the non-public address {}",
+            self.host, self.addr
+        )
+    }
+}
+
+impl Error for BlockedDestination {}
+
+/// Resolves upstream hosts, dropping addresses in blocked networks. The domain
+/// allowlist only looks at names, this makes sure they don't lead somewhere
+/// internal.
+///
+/// The guard only covers hosts resolved locally. With `PROXY` set, the proxy
+/// host itself is exempt, and upstream hosts are only checked when the proxy
+/// lets us resolve them, as with `socks5://`. An HTTP proxy or `socks5h://`
+/// resolves them on its own end, out of reach of the guard.
+pub struct GuardedResolver {
+    /// Host of the configured `PROXY`, which is usually on a private network
+    /// such as the one of a Docker Compose setup, and always reachable.
+    proxy_host: Option<String>,
+}
+
+impl GuardedResolver {
+    pub fn new(proxy: Option<&str>) -> Self {
+        let proxy_host = proxy
+            .and_then(|proxy| Url::parse(proxy).ok())
+            .and_then(|proxy| proxy.host_str().map(str::to_string));
+
+        GuardedResolver { proxy_host }
+    }
+}
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let proxy_host = self.proxy_host.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .collect::<Vec<SocketAddr>>();
+
+            if proxy_host.as_deref() == Some(host.as_str()) {
+                return Ok(Box::new(addrs.into_iter()) as Addrs);
+            }
+
+            let (allowed, blocked): (Vec<_>, Vec<_>) =
+                addrs.into_iter().partition(|addr| !is_blocked(addr.ip()));
+
+            if allowed.is_empty() {
+                if let Some(addr) = blocked.first() {
+                    let error = BlockedDestination {
+                        host,
+                        addr: addr.ip(),
+                    };
+                    tracing::warn!("SSRF guard: {}", error);
+                    return Err(error.into());
+                }
+            }
+
+            Ok(Box::new(allowed.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_private_networks() {
+        assert!(is_blocked("172.17.0.2".parse().unwrap()));
+        assert!(is_blocked("169.254.169.254".parse().unwrap()));
+        assert!(is_blocked("::1".parse().unwrap()));
+        assert!(!is_blocked("142.250.185.78".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn proxy_host_is_exempt() {
+        let addrs = GuardedResolver::new(Some("socks5://localhost:1080"))
+            .resolve("localhost".parse().unwrap())
+            .await
+            .expect("proxy host blocked");
+        assert!(addrs.into_iter().any(|addr| addr.ip().is_loopback()));
+    }
+}
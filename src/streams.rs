@@ -0,0 +1,266 @@
+use crate::utils;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use futures_util::task::AtomicWaker;
+use once_cell::sync::Lazy;
+use qstring::QString;
+use serde_json::json;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// Token required in `Authorization: Bearer <token>` by the admin API for
+/// streams, which is left out when unset.
+static ADMIN_TOKEN: Lazy<Option<String>> = Lazy::new(|| env::var("ADMIN_TOKEN").ok());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+static STREAMS: Lazy<Mutex<HashMap<u64, Arc<Stream>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// What a request is for, known once it has been validated.
+#[derive(Clone)]
+pub struct Target {
+    host: String,
+    video_id: Option<String>,
+    itag: Option<String>,
+}
+
+impl Target {
+    pub fn new(path: &str, host: &str, query: &QString) -> Target {
+        let video_id = if path == "/videoplayback" {
+            query.get("id").map(str::to_string)
+        } else {
+            // thumbnails such as /vi/<id>/hqdefault.jpg
+            let mut segments = path.split('/').skip(1);
+            segments
+                .next()
+                .filter(|segment| *segment == "vi" || *segment == "vi_webp")
+                .and(segments.next())
+                .map(str::to_string)
+        };
+
+        Target {
+            host: host.to_string(),
+            video_id,
+            itag: query.get("itag").map(str::to_string),
+        }
+    }
+}
+
+struct Stream {
+    request_id: String,
+    client: Option<IpAddr>,
+    target: Target,
+    started: Instant,
+    bytes: AtomicU64,
+    cancelled: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Stream {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.waker.wake();
+    }
+}
+
+#[derive(Debug)]
+struct Terminated;
+
+impl fmt::Display for Terminated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Stream terminated by an admin")
+    }
+}
+
+impl Error for Terminated {}
+
+/// A streamed body listed in the table of active streams until dropped.
+struct TrackedBody<B> {
+    inner: B,
+    id: u64,
+    stream: Arc<Stream>,
+}
+
+impl<B> MessageBody for TrackedBody<B>
+where
+    B: MessageBody + Unpin,
+{
+    type Error = Box<dyn Error>;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+
+        this.stream.waker.register(cx.waker());
+        if this.stream.cancelled.load(Ordering::Relaxed) {
+            // an error rather than the end of the body, so the connection is
+            // closed instead of the response looking complete
+            return Poll::Ready(Some(Err(Terminated.into())));
+        }
+
+        match Pin::new(&mut this.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(bytes))) => {
+                this.stream
+                    .bytes
+                    .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                Poll::Ready(Some(Ok(bytes)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<B> Drop for TrackedBody<B> {
+    fn drop(&mut self) {
+        STREAMS.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Lists a streamed response as an active stream, which can be terminated
+/// through the admin API. Other responses are returned as they are.
+pub fn register(
+    response: HttpResponse,
+    req: &HttpRequest,
+    client: Option<IpAddr>,
+    request_id: &str,
+) -> HttpResponse {
+    if !matches!(response.body().size(), BodySize::Stream) {
+        return response;
+    }
+    let Some(target) = req.extensions().get::<Target>().cloned() else {
+        return response;
+    };
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let stream = Arc::new(Stream {
+        request_id: request_id.to_string(),
+        client,
+        target,
+        started: Instant::now(),
+        bytes: AtomicU64::new(0),
+        cancelled: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    });
+    STREAMS.lock().unwrap().insert(id, stream.clone());
+
+    response
+        .map_body(|_, inner| TrackedBody { inner, id, stream })
+        .map_into_boxed_body()
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    if ADMIN_TOKEN.is_some() {
+        cfg.route("/streams", web::get().to(list))
+            .route("/streams", web::delete().to(terminate_client))
+            .route("/streams/{id}", web::delete().to(terminate));
+    }
+}
+
+fn is_authorized(req: &HttpRequest) -> bool {
+    let Some(token) = ADMIN_TOKEN.as_ref() else {
+        return false;
+    };
+
+    req.headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| utils::constant_time_eq(given.as_bytes(), token.as_bytes()))
+}
+
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header(("WWW-Authenticate", "Bearer"))
+        .finish()
+}
+
+async fn list(req: HttpRequest) -> HttpResponse {
+    if !is_authorized(&req) {
+        return unauthorized();
+    }
+
+    let streams = STREAMS.lock().unwrap();
+    let mut streams = streams.iter().collect::<Vec<_>>();
+    streams.sort_by_key(|(id, _)| **id);
+
+    let streams = streams
+        .into_iter()
+        .map(|(id, stream)| {
+            let age = stream.started.elapsed().as_secs_f64();
+            let bytes = stream.bytes.load(Ordering::Relaxed);
+            json!({
+                "id": id,
+                "request_id": stream.request_id,
+                "client": stream.client.map(|ip| ip.to_string()),
+                "host": stream.target.host,
+                "video_id": stream.target.video_id,
+                "itag": stream.target.itag,
+                "bytes": bytes,
+                "bytes_per_second": if age > 0.0 { (bytes as f64 / age) as u64 } else { 0 },
+                "age_seconds": age as u64,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    HttpResponse::Ok().json(json!({ "streams": streams }))
+}
+
+/// Terminates a single stream by its id.
+async fn terminate(req: HttpRequest, id: web::Path<u64>) -> HttpResponse {
+    if !is_authorized(&req) {
+        return unauthorized();
+    }
+
+    let id = id.into_inner();
+    let Some(stream) = STREAMS.lock().unwrap().get(&id).cloned() else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    stream.cancel();
+    tracing::info!("Terminated stream {} of request {}", id, stream.request_id);
+    HttpResponse::NoContent().finish()
+}
+
+/// Terminates every stream of the client given as `?client=<ip>`.
+async fn terminate_client(req: HttpRequest) -> HttpResponse {
+    if !is_authorized(&req) {
+        return unauthorized();
+    }
+
+    let query = QString::from(req.query_string());
+    let Some(client) = query
+        .get("client")
+        .and_then(|client| client.parse::<IpAddr>().ok())
+    else {
+        return HttpResponse::BadRequest().body("Invalid client provided");
+    };
+
+    let client = client.to_canonical();
+    let streams = STREAMS.lock().unwrap();
+    let matching = streams
+        .values()
+        .filter(|stream| stream.client.map(|ip| ip.to_canonical()) == Some(client))
+        .collect::<Vec<_>>();
+    matching.iter().for_each(|stream| stream.cancel());
+    let terminated = matching.len();
+    tracing::info!("Terminated {} streams of {}", terminated, client);
+
+    HttpResponse::Ok().json(json!({ "terminated": terminated }))
+}
@@ -0,0 +1,80 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+
+/// A request as received by a stub server.
+pub struct StubRequest {
+    /// The request target, i.e. the path with the query string.
+    pub target: String,
+    #[cfg_attr(not(feature = "otel"), allow(dead_code))]
+    pub body: Vec<u8>,
+}
+
+/// Starts an HTTP server on a local port for tests, answering every request
+/// with a 200 and the body returned by `respond`. Returns the base URL.
+///
+/// It runs on a thread of its own, so it serves blocking clients as well as
+/// async ones.
+pub fn serve<F>(respond: F) -> String
+where
+    F: Fn(StubRequest) -> Vec<u8> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        for socket in listener.incoming() {
+            let Ok(mut socket) = socket else {
+                continue;
+            };
+            let Some(request) = read_request(&mut socket) else {
+                continue;
+            };
+
+            let body = respond(request);
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = socket
+                .write_all(head.as_bytes())
+                .and_then(|_| socket.write_all(&body));
+        }
+    });
+
+    base
+}
+
+fn read_request(socket: &mut impl Read) -> Option<StubRequest> {
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+
+    let (head_len, content_length) = loop {
+        let len = socket.read(&mut buf).ok().filter(|len| *len > 0)?;
+        request.extend_from_slice(&buf[..len]);
+
+        let head = String::from_utf8_lossy(&request);
+        if let Some(head_len) = head.find("\r\n\r\n") {
+            let content_length = head[..head_len]
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            break (head_len + 4, content_length);
+        }
+    };
+
+    while request.len() < head_len + content_length {
+        let len = socket.read(&mut buf).ok().filter(|len| *len > 0)?;
+        request.extend_from_slice(&buf[..len]);
+    }
+
+    let head = String::from_utf8_lossy(&request[..head_len]);
+    let target = head.split(' ').nth(1).unwrap_or_default().to_string();
+
+    Some(StubRequest {
+        target,
+        body: request[head_len..].to_vec(),
+    })
+}
@@ -0,0 +1,121 @@
+use crate::utils;
+use bytes::Bytes;
+use futures_util::Stream;
+use once_cell::sync::Lazy;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+
+struct Config {
+    /// Maximum bytes per second of a single stream.
+    per_connection: Option<u64>,
+    /// Maximum bytes per second of all streams together, shared evenly.
+    global: Option<u64>,
+    /// Bytes sent at the start of each stream before throttling kicks in.
+    burst: u64,
+}
+
+static CONFIG: Lazy<Config> = Lazy::new(|| Config {
+    per_connection: Some(utils::get_env_number("THROTTLE_CONNECTION", 0)).filter(|r| *r > 0),
+    global: Some(utils::get_env_number("THROTTLE_GLOBAL", 0)).filter(|r| *r > 0),
+    burst: utils::get_env_number("THROTTLE_BURST", 0),
+});
+
+static ACTIVE_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+fn current_rate() -> Option<u64> {
+    let global_share = CONFIG.global.map(|global| {
+        let active = ACTIVE_STREAMS.load(Ordering::Relaxed).max(1) as u64;
+        (global / active).max(1)
+    });
+
+    match (CONFIG.per_connection, global_share) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+pub struct ThrottledStream<S> {
+    inner: S,
+    enabled: bool,
+    burst_remaining: u64,
+    next_allowed: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S, E> ThrottledStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    pub fn new(stream: S) -> Self {
+        let enabled = CONFIG.per_connection.is_some() || CONFIG.global.is_some();
+
+        if enabled {
+            ACTIVE_STREAMS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        ThrottledStream {
+            inner: stream,
+            enabled,
+            burst_remaining: CONFIG.burst,
+            next_allowed: Instant::now(),
+            sleep: None,
+        }
+    }
+}
+
+impl<S> Drop for ThrottledStream<S> {
+    fn drop(&mut self) {
+        if self.enabled {
+            ACTIVE_STREAMS.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<S, E> Stream for ThrottledStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if !this.enabled {
+            return Pin::new(&mut this.inner).poll_next(cx);
+        }
+
+        if let Some(sleep) = this.sleep.as_mut() {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.sleep = None;
+        }
+
+        let item = match Pin::new(&mut this.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(bytes))) => bytes,
+            other => return other,
+        };
+
+        let len = item.len() as u64;
+        let throttled = len.saturating_sub(this.burst_remaining);
+        this.burst_remaining = this.burst_remaining.saturating_sub(len);
+
+        if throttled > 0 {
+            if let Some(rate) = current_rate() {
+                let now = Instant::now();
+                let start = this.next_allowed.max(now);
+                this.next_allowed = start + Duration::from_secs_f64(throttled as f64 / rate as f64);
+
+                if this.next_allowed > now {
+                    this.sleep = Some(Box::pin(tokio::time::sleep_until(this.next_allowed)));
+                }
+            }
+        }
+
+        Poll::Ready(Some(Ok(item)))
+    }
+}
@@ -0,0 +1,87 @@
+use crate::utils;
+use actix_web::{HttpMessage, HttpRequest};
+use once_cell::sync::Lazy;
+use std::time::Duration;
+
+/// Send a `Server-Timing` header with the phases measured for the request.
+static ENABLED: Lazy<bool> = Lazy::new(|| utils::get_env_bool("SERVER_TIMING"));
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Verifying the qhash of the URL.
+    #[cfg_attr(not(feature = "qhash"), allow(dead_code))]
+    Qhash,
+    /// Waiting for the response headers of upstream.
+    Upstream,
+    /// Transcoding an image or rewriting a manifest.
+    Transform,
+}
+
+impl Phase {
+    fn as_str(self) -> &'static str {
+        match self {
+            Phase::Qhash => "qhash",
+            Phase::Upstream => "upstream",
+            Phase::Transform => "transform",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Phase::Qhash => "qhash check",
+            Phase::Upstream => "upstream time to first byte",
+            Phase::Transform => "transform",
+        }
+    }
+}
+
+/// The phases of a request in the order they were measured.
+struct Timings(Vec<(Phase, Duration)>);
+
+pub fn is_enabled() -> bool {
+    *ENABLED
+}
+
+/// Adds a phase to the timings of the request, repeated phases add up.
+pub fn record(req: &HttpRequest, phase: Phase, duration: Duration) {
+    if !is_enabled() {
+        return;
+    }
+
+    let mut extensions = req.extensions_mut();
+    let Some(timings) = extensions.get_mut::<Timings>() else {
+        extensions.insert(Timings(vec![(phase, duration)]));
+        return;
+    };
+
+    match timings
+        .0
+        .iter_mut()
+        .find(|(measured, _)| *measured == phase)
+    {
+        Some((_, total)) => *total += duration,
+        None => timings.0.push((phase, duration)),
+    }
+}
+
+/// The `Server-Timing` header of the request, if enabled and anything was measured.
+pub fn header(req: &HttpRequest) -> Option<String> {
+    let extensions = req.extensions();
+    let timings = extensions.get::<Timings>()?;
+
+    let header = timings
+        .0
+        .iter()
+        .map(|(phase, duration)| {
+            format!(
+                "{};dur={:.1};desc=\"{}\"",
+                phase.as_str(),
+                duration.as_secs_f64() * 1000.0,
+                phase.description()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Some(header)
+}
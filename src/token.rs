@@ -0,0 +1,229 @@
+use crate::utils;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use once_cell::sync::Lazy;
+use qstring::QString;
+use reqwest::Url;
+use std::env;
+
+const NONCE_LEN: usize = 24;
+
+static CIPHER: Lazy<Option<XChaCha20Poly1305>> =
+    Lazy::new(|| Some(cipher(&env::var("TOKEN_KEY").ok()?)));
+
+static TOKEN_TTL: Lazy<u64> = Lazy::new(|| utils::get_env_number("TOKEN_TTL", 0));
+
+fn cipher(secret: &str) -> XChaCha20Poly1305 {
+    let key = blake3::derive_key("piped-proxy token key", secret.as_bytes());
+    XChaCha20Poly1305::new(&key.into())
+}
+
+pub fn is_enabled() -> bool {
+    CIPHER.is_some()
+}
+
+/// Whether plain proxy URLs are refused, so that only tokens are accepted.
+pub fn is_required() -> bool {
+    is_enabled() && utils::get_env_bool("TOKEN_ONLY")
+}
+
+/// Seals a proxy path and query into an opaque `/t/<token>/` path. The trailing
+/// slash lets relative URLs in manifests resolve below the token.
+pub fn seal(path: &str, query: &str) -> Option<String> {
+    seal_with(CIPHER.as_ref()?, *TOKEN_TTL, path, query)
+}
+
+fn seal_with(cipher: &XChaCha20Poly1305, ttl: u64, path: &str, query: &str) -> Option<String> {
+    let expires = match ttl {
+        0 => 0,
+        ttl => utils::now() + ttl,
+    };
+    let plaintext = format!("{}\n{}?{}", expires, path, query);
+
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, plaintext.as_bytes()).ok()?;
+
+    let mut token = nonce.to_vec();
+    token.extend_from_slice(&ciphertext);
+
+    Some(format!("/t/{}/", URL_SAFE_NO_PAD.encode(token)))
+}
+
+/// Whether a path after a token is one of the relative playlist or segment URLs
+/// `localize_url` leaves as they are. Dot segments, encoded or not, are refused
+/// so the path stays below the sealed one.
+fn is_relative_name(relative: &str) -> bool {
+    (relative.ends_with(".m3u8") || relative.ends_with(".ts"))
+        && !relative.contains('\\')
+        && relative.split('/').all(|segment| {
+            let segment = segment.to_ascii_lowercase().replace("%2e", ".");
+            !segment.is_empty() && segment != "." && segment != ".."
+        })
+}
+
+/// Opens a `/t/<token>/` path, returning the sealed path and query. Anything after
+/// the token is resolved relative to the sealed path, only keeping the `host` of
+/// the sealed query, like `localize_url` does for relative URLs. The parameters in
+/// `utils::CLIENT_PARAMS` are taken over from the query string, everything else
+/// has to come from the sealed token.
+/// Returns `Ok(None)` for paths without a token.
+pub fn open(path: &str, query: &str) -> Result<Option<(String, String)>, &'static str> {
+    let Some(token) = path.strip_prefix("/t/") else {
+        return Ok(None);
+    };
+
+    let Some(cipher) = CIPHER.as_ref() else {
+        return Err("Tokens are not enabled");
+    };
+
+    open_with(cipher, token, query).map(Some)
+}
+
+/// Opens what follows `/t/` in a path, the token and an optional relative path.
+fn open_with(
+    cipher: &XChaCha20Poly1305,
+    token: &str,
+    query: &str,
+) -> Result<(String, String), &'static str> {
+    let (token, relative) = token.split_once('/').unwrap_or((token, ""));
+
+    let token = URL_SAFE_NO_PAD
+        .decode(token)
+        .map_err(|_| "Invalid token provided")?;
+
+    if token.len() <= NONCE_LEN {
+        return Err("Invalid token provided");
+    }
+
+    let (nonce, ciphertext) = token.split_at(NONCE_LEN);
+    let plaintext = cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Invalid token provided")?;
+    let plaintext = String::from_utf8(plaintext).map_err(|_| "Invalid token provided")?;
+
+    let Some((expires, url)) = plaintext.split_once('\n') else {
+        return Err("Invalid token provided");
+    };
+    let expires = expires
+        .parse::<u64>()
+        .map_err(|_| "Invalid token provided")?;
+    if expires != 0 && utils::now() > expires {
+        return Err("Token expired");
+    }
+
+    let (sealed_path, sealed_query) = url.split_once('?').unwrap_or((url, ""));
+
+    let (path, mut merged) = if relative.is_empty() {
+        (sealed_path.to_string(), QString::from(sealed_query))
+    } else {
+        if !is_relative_name(relative) {
+            return Err("Invalid token path provided");
+        }
+
+        let base = Url::parse(&format!("https://token.invalid{}", sealed_path))
+            .map_err(|_| "Invalid token provided")?;
+        let path = base
+            .join(relative)
+            .map_err(|_| "Invalid token provided")?
+            .path()
+            .to_string();
+
+        let host = QString::from(sealed_query)
+            .get("host")
+            .map(|host| vec![("host", host.to_string())])
+            .unwrap_or_default();
+
+        (path, QString::new(host))
+    };
+
+    for (key, value) in QString::from(query).into_pairs() {
+        if utils::CLIENT_PARAMS.contains(&key.as_str()) && !merged.has(&key) {
+            merged.add_pair((key, value));
+        }
+    }
+
+    Ok((path, merged.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_names_stay_below_the_token() {
+        assert!(is_relative_name("index.m3u8"));
+        assert!(is_relative_name("720p/segment-1.ts"));
+
+        assert!(!is_relative_name("../videoplayback"));
+        assert!(!is_relative_name("../../api/manifest/hls.m3u8"));
+        assert!(!is_relative_name("%2e%2E/index.m3u8"));
+        assert!(!is_relative_name("a/%2e/b.ts"));
+        assert!(!is_relative_name("..\\index.m3u8"));
+        assert!(!is_relative_name("/index.m3u8"));
+        assert!(!is_relative_name("segment.mp4"));
+    }
+
+    /// The part of a sealed path after `/t/`.
+    fn token_for(cipher: &XChaCha20Poly1305, path: &str, query: &str) -> String {
+        let sealed = seal_with(cipher, 0, path, query).unwrap();
+        sealed.strip_prefix("/t/").unwrap().to_string()
+    }
+
+    #[test]
+    fn sealed_urls_open_again() {
+        let cipher = cipher("secret");
+        let token = token_for(&cipher, "/videoplayback", "host=rr1.googlevideo.com&id=1");
+        assert!(!token.contains("googlevideo"));
+
+        // client parameters are taken over, the sealed ones can't be replaced
+        let opened = open_with(&cipher, &token, "range=0-99&host=evil.example&id=2");
+        assert_eq!(
+            opened,
+            Ok((
+                "/videoplayback".to_string(),
+                "host=rr1.googlevideo.com&id=1&range=0-99".to_string()
+            ))
+        );
+
+        let token = token_for(
+            &cipher,
+            "/api/manifest/hls/index.m3u8",
+            "host=manifest.googlevideo.com&a=b",
+        );
+        let opened = open_with(&cipher, &format!("{}720p/index.m3u8", token), "");
+        assert_eq!(
+            opened,
+            Ok((
+                "/api/manifest/hls/720p/index.m3u8".to_string(),
+                "host=manifest.googlevideo.com".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let cipher = cipher("secret");
+        let token = token_for(&cipher, "/videoplayback", "host=rr1.googlevideo.com&id=1");
+        let token = token.trim_end_matches('/');
+
+        let mut bytes = URL_SAFE_NO_PAD.decode(token).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let tampered = URL_SAFE_NO_PAD.encode(bytes);
+        assert_eq!(
+            open_with(&cipher, &tampered, ""),
+            Err("Invalid token provided")
+        );
+
+        assert_eq!(
+            open_with(&super::cipher("other"), token, ""),
+            Err("Invalid token provided")
+        );
+        assert_eq!(
+            open_with(&cipher, &token[..NONCE_LEN], ""),
+            Err("Invalid token provided")
+        );
+    }
+}
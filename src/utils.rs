@@ -1,8 +1,21 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::HttpResponse;
+use bytes::Bytes;
 use qstring::QString;
 use reqwest::Url;
 use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Query parameters players add to proxy URLs themselves, which are carried over
+/// when a URL is refreshed or opened from a token.
+pub const CLIENT_PARAMS: [&str; 4] = ["range", "rewrite", "avif", "ump"];
 
 pub fn read_buf(buf: &[u8], pos: &mut usize) -> u8 {
     let byte = buf[*pos];
@@ -10,42 +23,34 @@ pub fn read_buf(buf: &[u8], pos: &mut usize) -> u8 {
     byte
 }
 
-fn finalize_url(path: &str, query: BTreeMap<String, String>) -> String {
-    #[cfg(feature = "qhash")]
+#[cfg_attr(not(feature = "qhash"), allow(unused_variables))]
+fn finalize_url(path: &str, query: BTreeMap<String, String>, client_ip: Option<IpAddr>) -> String {
+    #[cfg(feature = "tokens")]
     {
-        use std::collections::BTreeSet;
-
-        let qhash = {
-            let secret = env::var("HASH_SECRET");
-            if let Ok(secret) = secret {
-                let set = query
-                    .iter()
-                    .filter(|(key, _)| !matches!(key.as_str(), "qhash" | "range" | "rewrite"))
-                    .map(|(key, value)| (key.as_bytes().to_owned(), value.as_bytes().to_owned()))
-                    .collect::<BTreeSet<_>>();
-
-                let mut hasher = blake3::Hasher::new();
-
-                for (key, value) in set {
-                    hasher.update(&key);
-                    hasher.update(&value);
-                }
-
-                hasher.update(path.as_bytes());
-
-                hasher.update(secret.as_bytes());
-
-                let hash = hasher.finalize().to_hex();
-
-                Some(hash[..8].to_owned())
-            } else {
-                None
-            }
-        };
+        let query = QString::new(
+            query
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect::<Vec<_>>(),
+        );
+        if let Some(token) = crate::token::seal(path, &query.to_string()) {
+            return token;
+        }
+    }
 
-        if let Some(qhash) = qhash {
+    #[cfg(feature = "qhash")]
+    {
+        let qhash = crate::qhash::sign(
+            query
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+            path,
+            client_ip,
+        );
+
+        if let Some((name, signature)) = qhash {
             let mut query = QString::new(query.into_iter().collect::<Vec<_>>());
-            query.add_pair(("qhash", qhash));
+            query.add_pair((name, signature));
             return format!("{}?{}", path, query);
         }
     }
@@ -54,21 +59,62 @@ fn finalize_url(path: &str, query: BTreeMap<String, String>) -> String {
     format!("{}?{}", path, query)
 }
 
-pub fn localize_url(url: &str, host: &str) -> String {
+/// Adds the API key the request carried in its query string to a localized URL,
+/// so players fetching what a manifest lists are let through as well.
+fn with_api_key(url: String, api_key: Option<&str>) -> String {
+    let Some(api_key) = api_key else {
+        return url;
+    };
+
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let pair = QString::new(vec![(crate::api_keys::QUERY_PARAM, api_key)]);
+    format!("{}{}{}", url, separator, pair)
+}
+
+/// An absolute URL we can't make sense of, left untouched. With tokens, it is
+/// dropped instead, as they are meant to hide upstream URLs.
+fn unlocalized(url: &str) -> String {
+    #[cfg(feature = "tokens")]
+    if crate::token::is_enabled() {
+        return String::new();
+    }
+
+    url.to_string()
+}
+
+pub fn localize_url(
+    url: &str,
+    host: &str,
+    client_ip: Option<IpAddr>,
+    api_key: Option<&str>,
+) -> String {
     if url.starts_with("https://") {
-        let url = Url::parse(url).unwrap();
-        let host = url.host().unwrap().to_string();
+        let Ok(parsed) = Url::parse(url) else {
+            return unlocalized(url);
+        };
+        let Some(host) = parsed.host_str().map(|host| host.to_string()) else {
+            return unlocalized(url);
+        };
 
-        let mut query = url.query_pairs().into_owned().collect::<BTreeMap<_, _>>();
+        let mut query = parsed
+            .query_pairs()
+            .into_owned()
+            .collect::<BTreeMap<_, _>>();
 
         query.insert("host".to_string(), host.clone());
 
-        return finalize_url(url.path(), query);
+        return with_api_key(finalize_url(parsed.path(), query, client_ip), api_key);
     } else if url.ends_with(".m3u8") || url.ends_with(".ts") {
+        // relative URLs resolve below the token of the manifest by themselves
+        #[cfg(feature = "tokens")]
+        if crate::token::is_enabled() {
+            return with_api_key(url.to_string(), api_key);
+        }
+
         let mut query = BTreeMap::new();
         query.insert("host".to_string(), host.to_string());
 
-        return finalize_url(url, query);
+        return with_api_key(finalize_url(url, query, client_ip), api_key);
     }
 
     url.to_string()
@@ -95,9 +141,110 @@ pub fn escape_xml(raw: &str) -> Cow<'_, str> {
     }
 }
 
+/// The current Unix time in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
 pub fn get_env_bool(key: &str) -> bool {
     match env::var(key) {
         Ok(val) => val.to_lowercase() == "true" || val == "1",
         Err(_) => false,
     }
 }
+
+pub fn get_env_number<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(default)
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// A file named by an environment variable, reloaded whenever it is modified.
+pub struct WatchedFile {
+    path: String,
+    modified: Option<SystemTime>,
+}
+
+impl WatchedFile {
+    pub fn from_env(key: &str) -> Option<WatchedFile> {
+        env::var(key).ok().map(|path| WatchedFile {
+            path,
+            modified: None,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Reads the file if it changed since it was last loaded.
+    pub fn load_if_changed(&mut self) -> Option<String> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+
+        if modified.is_some() && modified == self.modified {
+            return None;
+        }
+
+        match fs::read_to_string(&self.path) {
+            Ok(content) => {
+                self.modified = modified;
+                Some(content)
+            }
+            Err(e) => {
+                tracing::warn!("Could not read {}: {}", self.path, e);
+                None
+            }
+        }
+    }
+}
+
+/// A response body reporting the size of every chunk it sends.
+pub struct CountedBody<B, F> {
+    inner: B,
+    on_chunk: F,
+}
+
+impl<B, F> MessageBody for CountedBody<B, F>
+where
+    B: MessageBody + Unpin,
+    F: FnMut(usize) + Unpin,
+{
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(bytes))) = &poll {
+            (this.on_chunk)(bytes.len());
+        }
+
+        poll
+    }
+}
+
+pub fn count_body<F>(response: HttpResponse, on_chunk: F) -> HttpResponse
+where
+    F: FnMut(usize) + Unpin + 'static,
+{
+    response
+        .map_body(|_, inner| CountedBody { inner, on_chunk })
+        .map_into_boxed_body()
+}